@@ -21,6 +21,10 @@ pub(crate) fn call_back(conn: &Kcp2kConnection, cb: Callback) {
         }
         CallbackType::OnError => {}
         CallbackType::OnDisconnected => {}
+        CallbackType::OnRemoved => {}
+        CallbackType::OnReceipt => {}
+        CallbackType::OnChallenge => {}
+        CallbackType::OnSuspect | CallbackType::OnResumed => {}
     }
 }
 
@@ -28,9 +32,9 @@ fn main() {
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("debug")).init();
     let config = Kcp2KConfig::default();
 
-    let client = Kcp2KClient::new(config, call_back);
+    let client = Kcp2KClient::new(config, Box::new(call_back)).unwrap();
 
-    client.connect("127.0.0.1:3100".to_string());
+    client.connect("127.0.0.1:3100".to_string()).unwrap();
 
     loop {
         client.tick();
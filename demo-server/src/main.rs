@@ -23,7 +23,7 @@ fn main() {
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("debug")).init();
     let config = Kcp2KConfig::default();
 
-    let server = Kcp2KServer::new("0.0.0.0:3100".to_string(), config, call_back);
+    let server = Kcp2KServer::new("0.0.0.0:3100".to_string(), config, Box::new(call_back)).unwrap();
 
     loop {
         server.tick();
@@ -1,8 +1,11 @@
-use crate::kcp2k_common::{configure_socket_buffers, CallbackFuncType, Kcp2KError};
-use crate::kcp2k_config::Kcp2KConfig;
+use crate::kcp2k_common::{configure_socket_buffers, CallbackFuncType, Kcp2KError, SharedDataCallbackType};
+use crate::kcp2k_config::{Kcp2KConfig, RecvStrategy, SocketMode};
+use log::{error, warn};
 use revel_cell::arc::Arc;
 use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use std::io::ErrorKind;
 use std::mem::MaybeUninit;
+use std::time::{Duration, Instant};
 
 #[derive(Debug, PartialEq, Copy, Clone)]
 #[repr(u8)]
@@ -16,68 +19,152 @@ pub enum Kcp2KMode {
 pub struct Kcp2K {
     pub(crate) config: Arc<Kcp2KConfig>,
     pub(crate) socket: Arc<Socket>,
-    pub(crate) callback_func: CallbackFuncType,
+    pub(crate) callback_func: Arc<CallbackFuncType>,
+    pub(crate) data_callback_func: Arc<Option<SharedDataCallbackType>>,
+    pub(crate) oversized_datagrams: Arc<u64>,
+    pub(crate) last_tick_time: Arc<Option<Instant>>,
+    pub(crate) slow_ticks: Arc<u64>,
+    pub(crate) last_slow_tick_warning: Arc<Option<Instant>>,
 }
 
 impl Kcp2K {
-    pub(crate) fn raw_receive_from(&self) -> Option<(SockAddr, Vec<u8>)> {
-        // 1. 申请接收缓冲区（MTU）
-        let mut buf: Vec<MaybeUninit<u8>> = Vec::with_capacity(self.config.mtu);
+    // 没有数据可读时返回 Ok(None)，其他 socket 错误以 Kcp2KError::Io 返回
+    pub(crate) fn raw_receive_from(&self, socket: &Socket) -> Result<Option<(SockAddr, Vec<u8>)>, Kcp2KError> {
+        // 1. 申请接收缓冲区（MTU + 1），多出的 1 字节用于检测超过 MTU 而被截断的数据报
+        let mut buf: Vec<MaybeUninit<u8>> = Vec::with_capacity(self.config.mtu + 1);
 
         unsafe {
-            buf.set_len(self.config.mtu); // 必须
+            buf.set_len(self.config.mtu + 1); // 必须
         }
 
         // 2. 调用 socket2 recv_from（官方签名）
-        let (size, addr) = match self.socket.recv_from(&mut buf) {
+        let (size, addr) = match socket.recv_from(&mut buf) {
             Ok(x) => x,
-            Err(_) => return None,
+            // 非阻塞模式下没有数据，或阻塞模式下等待超时
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted) => return Ok(None),
+            Err(e) => return Err(Kcp2KError::io("recv_from", &e)),
         };
 
-        // 检查接收数据大小是否超过 MTU
+        // 检查接收数据大小是否超过 MTU，被截断的数据报不能交给 KCP 处理，直接丢弃并计数
         if size > self.config.mtu {
-            return None;
+            self.oversized_datagrams.set_value(*self.oversized_datagrams + 1);
+            error!(instance = self.config.instance_name.as_deref(); "{} {}", self.config.log_prefix(), Kcp2KError::InvalidReceive(format!("Dropped oversized datagram from {:?}: larger than mtu={}", addr.as_socket(), self.config.mtu)));
+            return Ok(Some((addr, Vec::new())));
         }
 
         // 3. 将 MaybeUninit 转成 &[u8]（官方安全惯用法）
         let data = unsafe { std::slice::from_raw_parts(buf.as_ptr() as *const u8, size) };
 
         // 4. 转成 Bytes（必须 copy，但只复制一次）
-        Some((addr, data.to_vec()))
+        Ok(Some((addr, data.to_vec())))
+    }
+
+    // 记录两次 tick 之间的间隔，明显超过 KCP interval 时计数并限频输出警告
+    pub(crate) fn record_tick(&self, now: Instant) {
+        if let Some(last_tick_time) = *self.last_tick_time.value() {
+            let spacing = now.saturating_duration_since(last_tick_time);
+            let limit = Duration::from_millis(self.config.interval as u64 * Kcp2KConfig::SLOW_TICK_FACTOR);
+            if spacing > limit {
+                self.slow_ticks.set_value(*self.slow_ticks + 1);
+                let warn_interval = Duration::from_millis(Kcp2KConfig::SLOW_TICK_WARNING_INTERVAL);
+                if self.last_slow_tick_warning.is_none_or(|last| now.saturating_duration_since(last) >= warn_interval) {
+                    self.last_slow_tick_warning.set_value(Some(now));
+                    warn!(instance = self.config.instance_name.as_deref(); "{} tick() was called {}ms after the previous tick, more than {}x the KCP interval of {}ms. Call tick() more often or increase the interval.", self.config.log_prefix(), spacing.as_millis(), Kcp2KConfig::SLOW_TICK_FACTOR, self.config.interval);
+                }
+            }
+        }
+        self.last_tick_time.set_value(Some(now));
+    }
+
+    // 按照配置的接收策略读取数据包，并交给 handler 处理
+    pub(crate) fn receive_with_strategy<F: FnMut(&SockAddr, &[u8])>(&self, handler: F) {
+        self.receive_with_strategy_from(&self.socket, handler)
+    }
+
+    // 按照配置的接收策略从指定的 socket 读取数据包，用于服务器同时监听多个地址
+    pub(crate) fn receive_with_strategy_from<F: FnMut(&SockAddr, &[u8])>(&self, socket: &Socket, mut handler: F) {
+        let start = Instant::now();
+        let mut count = 0;
+        loop {
+            // 检查是否达到本次 tick 的读取上限
+            match self.config.recv_strategy {
+                RecvStrategy::DrainAll => {}
+                RecvStrategy::Bounded(max) => {
+                    if count >= max {
+                        break;
+                    }
+                }
+                RecvStrategy::TimeBudget(budget) => {
+                    if start.elapsed() >= budget {
+                        break;
+                    }
+                }
+                RecvStrategy::External => break,
+            }
+            match self.raw_receive_from(socket) {
+                Ok(Some((sock_addr, data))) => {
+                    // 被丢弃的超大数据报不交给 handler，但继续读取
+                    if !data.is_empty() {
+                        handler(&sock_addr, &data);
+                    }
+                    count += 1;
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    warn!(instance = self.config.instance_name.as_deref(); "{} {}", self.config.log_prefix(), e);
+                    break;
+                }
+            }
+        }
     }
 }
 
 #[allow(unused)]
 impl Kcp2K {
-    pub fn new(config: Kcp2KConfig, callback: CallbackFuncType) -> Self {
+    pub fn new(config: Kcp2KConfig, callback: CallbackFuncType) -> Result<Self, Kcp2KError> {
+        config.validate()?;
+        let socket = Self::create_socket(&config)?;
+        Self::with_socket(config, socket, callback)
+    }
+
+    // 使用已经创建好的 socket，用于服务器绑定多个地址
+    pub(crate) fn with_socket(config: Kcp2KConfig, socket: Socket, callback: CallbackFuncType) -> Result<Self, Kcp2KError> {
+        config.validate()?;
+        Ok(Self {
+            config: Arc::new(config),
+            socket: Arc::new(socket),
+            callback_func: Arc::new(callback),
+            data_callback_func: Default::default(),
+            oversized_datagrams: Default::default(),
+            last_tick_time: Default::default(),
+            slow_ticks: Default::default(),
+            last_slow_tick_warning: Default::default(),
+        })
+    }
+
+    // 根据配置创建并设置 socket
+    pub(crate) fn create_socket(config: &Kcp2KConfig) -> Result<Socket, Kcp2KError> {
         let domain = match config.dual_mode {
             true => Domain::IPV6,
             false => Domain::IPV4,
         };
-        let socket = match Socket::new(domain, Type::DGRAM, Some(Protocol::UDP)) {
-            Ok(v) => v,
-            Err(e) => panic!("{}", Kcp2KError::Unexpected(e.to_string())),
-        };
-        if let Err(e) = configure_socket_buffers(&socket, &config) {
-            panic!("{}", Kcp2KError::Unexpected(e.to_string()));
-        }
-        if let Err(e) = socket.set_nonblocking(true) {
-            panic!("{}", Kcp2KError::Unexpected(e.to_string()));
-        }
+        Self::create_socket_with_domain(domain, config)
+    }
 
-        let kcp2k = Self {
-            config: Arc::new(config),
-            socket: Arc::new(socket),
-            callback_func: callback,
+    // 创建指定地址族的 socket 并按照配置设置
+    pub(crate) fn create_socket_with_domain(domain: Domain, config: &Kcp2KConfig) -> Result<Socket, Kcp2KError> {
+        let socket = Socket::new(domain, Type::DGRAM, Some(Protocol::UDP)).map_err(|e| Kcp2KError::io("failed to create socket", &e))?;
+        configure_socket_buffers(&socket, config).map_err(|e| Kcp2KError::io("failed to configure socket buffers", &e))?;
+        let result = match config.socket_mode {
+            SocketMode::NonBlocking => socket.set_nonblocking(true),
+            SocketMode::BlockingWithTimeout(timeout) => socket.set_nonblocking(false).and_then(|_| socket.set_read_timeout(Some(timeout))),
         };
-
-        kcp2k
+        result.map_err(|e| Kcp2KError::io("failed to set socket mode", &e))?;
+        Ok(socket)
     }
 
     pub fn stop(&self) -> Result<(), Kcp2KError> {
-        match self.socket.shutdown(std::net::Shutdown::Both) {
-            Ok(_) => Ok(()),
-            Err(e) => Err(Kcp2KError::Unexpected(e.to_string())),
-        }
+        self.socket.shutdown(std::net::Shutdown::Both)?;
+        Ok(())
     }
 }
@@ -1,33 +1,58 @@
 use crate::kcp2k::{Kcp2K, Kcp2KMode};
-use crate::kcp2k_common::{connection_hash, CallbackFuncType, Kcp2KChannel, Kcp2KConnectionStates, Kcp2KError};
+use crate::kcp2k_common::{connection_hash, pending_recv_bytes, resolve_socket_addrs, CallbackFuncType, ConnId, DataCallbackFuncType, Kcp2KChannel, Kcp2KConnectionStates, Kcp2KError};
 use crate::kcp2k_config::Kcp2KConfig;
 use crate::kcp2k_connection::Kcp2kConnection;
 use log::{error, info};
 use revel_cell::arc::Arc;
-use socket2::SockAddr;
-use std::io::Error;
-use std::net::SocketAddr;
+use socket2::{SockAddr, Socket};
+use std::future::poll_fn;
+use std::io::{Error, ErrorKind};
+use std::task::Poll;
+use std::time::Instant;
 
 pub struct Kcp2KClient {
     kcp2k: Kcp2K,
     connection: Arc<Option<Kcp2kConnection>>,
+    local_addr: Arc<Option<SockAddr>>,
+    // connect_without_hello 之后、send_hello_now 之前，待创建连接的地址
+    pending_hello_addr: Arc<Option<SockAddr>>,
+    // socket 是否由客户端自己创建；from_socket 传入的 socket 属于调用方，reset() 不能替换它
+    owns_socket: bool,
 }
 
 impl Kcp2KClient {
     fn create_connection(&self, sock_addr: SockAddr) {
-        let conn_id = connection_hash(&sock_addr);
-        self.connection.set_value(Some(Kcp2kConnection::new(conn_id, self.kcp2k.config.clone(), Arc::new(Kcp2KMode::Client), self.kcp2k.socket.clone(), Arc::new(sock_addr), self.kcp2k.callback_func)));
+        let conn_id = ConnId(connection_hash(&sock_addr));
+        self.connection.set_value(Some(Kcp2kConnection::new(conn_id, self.kcp2k.config.clone(), Arc::new(Kcp2KMode::Client), self.kcp2k.socket.clone(), Arc::new(sock_addr), self.kcp2k.callback_func.clone(), self.kcp2k.data_callback_func.clone())));
+    }
+
+    // 检测本机 socket 的本地地址变化（例如重新绑定网卡），保持 cookie 重新发送 Hello 让服务器尽快迁移连接。
+    // NAT 重新映射端口时本地地址不变，这里无法发现，由服务器根据后续数据包中的 cookie 向新地址发送质询，客户端回复 pong 后迁移连接
+    fn check_local_address(&self) {
+        let Some(conn) = self.connection.value() else {
+            return;
+        };
+        if *conn.state == Kcp2KConnectionStates::Disconnected {
+            return;
+        }
+        if let Ok(local_addr) = self.kcp2k.socket.local_addr()
+            && self.local_addr.as_ref() != Some(&local_addr)
+        {
+            info!(instance = self.kcp2k.config.instance_name.as_deref(); "{} Client local address changed: {:?} => {:?}", self.kcp2k.config.log_prefix(), self.local_addr.as_ref().and_then(|addr| addr.as_socket()), local_addr.as_socket());
+            self.local_addr.set_value(Some(local_addr.clone()));
+            conn.on_local_address_changed(local_addr);
+        }
     }
 
     fn handle_data(&self, sock_addr: &SockAddr, data: &[u8]) {
         // 如果连接存在，则处理数据
         match self.connection.value_mut() {
             None => {
-                error!("[KCP2K] No connection found for incoming data from {:?}", sock_addr);
+                error!(instance = self.kcp2k.config.instance_name.as_deref(); "{} No connection found for incoming data from {:?}", self.kcp2k.config.log_prefix(), sock_addr);
             }
             Some(conn) => {
                 if let Err(e) = conn.raw_input(data) {
-                    error!("Error reading from data: {}", e);
+                    error!(instance = self.kcp2k.config.instance_name.as_deref(); "{} Error reading from data: {}", self.kcp2k.config.log_prefix(), e);
                 }
             }
         }
@@ -35,63 +60,159 @@ impl Kcp2KClient {
 }
 
 impl Kcp2KClient {
-    pub fn new(config: Kcp2KConfig, callback: CallbackFuncType) -> Self {
-        let kcp2k = Kcp2K::new(config, callback);
-        let client = Kcp2KClient { kcp2k, connection: Default::default() };
-        client
+    pub fn new(config: Kcp2KConfig, callback: CallbackFuncType) -> Result<Self, Kcp2KError> {
+        let kcp2k = Kcp2K::new(config, callback)?;
+        Ok(Self::with_kcp2k(kcp2k, true))
     }
 
-    pub fn connect(&self, addr: String) {
-        let socket_addr = match addr.parse::<SocketAddr>() {
-            Ok(addr) => addr,
-            Err(e) => panic!("{}", Kcp2KError::Unexpected(e.to_string())),
-        };
-        if let Err(e) = self.kcp2k.socket.connect(&socket_addr.into()) {
-            panic!("{}", Kcp2KError::Unexpected(e.to_string()))
+    // 使用调用方已经创建好的 socket，socket 的阻塞模式与缓冲区大小由调用方设置，connect() 时仍会关联到服务器地址
+    pub fn from_socket(socket: Socket, config: Kcp2KConfig, callback: CallbackFuncType) -> Result<Self, Kcp2KError> {
+        Ok(Self::with_kcp2k(Kcp2K::with_socket(config, socket, callback)?, false))
+    }
+
+    fn with_kcp2k(kcp2k: Kcp2K, owns_socket: bool) -> Self {
+        Kcp2KClient {
+            kcp2k,
+            connection: Default::default(),
+            local_addr: Default::default(),
+            pending_hello_addr: Default::default(),
+            owns_socket,
         }
-        if let Ok(local_addr) = self.kcp2k.socket.local_addr()
-            && let Some(socket_addr) = local_addr.as_socket()
-        {
-            self.create_connection(socket_addr.into());
-            info!("[KCP2K] Client connecting to: {}", addr);
+    }
+
+    // 失败时不会创建连接，调用方可以换一个地址重试
+    pub fn connect(&self, addr: String) -> Result<(), Kcp2KError> {
+        self.connect_without_hello(addr)?;
+        self.send_hello_now();
+        Ok(())
+    }
+
+    // 只建立 socket 关联而不发送 Hello，之后调用 send_hello_now() 开始握手，便于自行控制握手时机
+    // 连接在 send_hello_now() 时才创建，因此超时与握手耗时从发送 Hello 开始计算
+    // addr 可以是 IP 字面量或 "主机名:端口"，依次尝试解析出的每个地址，直到 socket 关联成功
+    pub fn connect_without_hello(&self, addr: String) -> Result<(), Kcp2KError> {
+        let mut result = Ok(());
+        for socket_addr in resolve_socket_addrs(&addr, &self.kcp2k.config)? {
+            result = self.kcp2k.socket.connect(&socket_addr.into());
+            match &result {
+                Ok(()) => break,
+                Err(e) => error!(instance = self.kcp2k.config.instance_name.as_deref(); "{} Client failed to connect to {} ({}): {}", self.kcp2k.config.log_prefix(), addr, socket_addr, e),
+            }
         }
+        if let Err(e) = result {
+            return Err(Kcp2KError::ConnectionClosed(format!("failed to connect to {}: {}", addr, e)));
+        }
+        let local_addr = self.kcp2k.socket.local_addr().map_err(|e| Kcp2KError::ConnectionClosed(format!("failed to get local address: {}", e)))?;
+        let Some(socket_addr) = local_addr.as_socket() else {
+            return Err(Kcp2KError::Unexpected(format!("local address is not an inet address: {:?}", local_addr)));
+        };
+        self.local_addr.set_value(Some(local_addr.clone()));
+        self.pending_hello_addr.set_value(Some(socket_addr.into()));
+        info!(instance = self.kcp2k.config.instance_name.as_deref(); "{} Client connecting to: {}", self.kcp2k.config.log_prefix(), addr);
+        Ok(())
+    }
 
+    // 发送 Hello 开始握手，用于 connect_without_hello() 之后
+    pub fn send_hello_now(&self) {
+        if let Some(sock_addr) = self.pending_hello_addr.value_mut().take() {
+            self.create_connection(sock_addr);
+        }
         if let Some(connection) = self.connection.value_mut() {
             connection.send_hello();
         }
     }
 
     pub fn tick(&self) {
-        self.tick_incoming();
-        self.tick_outgoing();
+        self.tick_at(Instant::now());
+    }
+
+    // 使用调用方提供的当前时间执行 tick（例如引擎的帧时间）
+    pub fn tick_at(&self, now: Instant) {
+        self.kcp2k.record_tick(now);
+        self.tick_incoming_at(now);
+        self.tick_outgoing_at(now);
     }
 
     pub fn tick_incoming(&self) {
+        self.tick_incoming_at(Instant::now());
+    }
+
+    pub fn tick_incoming_at(&self, now: Instant) {
         if let Some(conn) = self.connection.value_mut()
             && *conn.state == Kcp2KConnectionStates::Disconnected
         {
             self.connection.set_value(None);
         }
 
-        while let Some((sock_addr, data)) = self.kcp2k.raw_receive_from() {
-            self.handle_data(&sock_addr, &data);
-        }
+        self.check_local_address();
+
+        self.kcp2k.receive_with_strategy(|sock_addr, data| self.handle_data(sock_addr, data));
 
         if let Some(conn) = self.connection.value_mut() {
-            conn.tick_incoming();
+            conn.tick_incoming_at(now);
         }
     }
 
     pub fn tick_outgoing(&self) {
+        self.tick_outgoing_at(Instant::now());
+    }
+
+    pub fn tick_outgoing_at(&self, now: Instant) {
         if let Some(conn) = self.connection.value_mut() {
-            conn.tick_outgoing();
+            conn.tick_outgoing_at(now);
         }
     }
 
+    // 等待连接建立：认证完成时返回 Ok，连接断开或不存在时返回错误。需要在其他任务中持续调用 tick()
+    pub async fn connected(&self) -> Result<(), Kcp2KError> {
+        poll_fn(|cx| match self.connection.value() {
+            None => Poll::Ready(Err(Kcp2KError::ConnectionClosed("Connection is closed".to_string()))),
+            Some(conn) => match *conn.state {
+                Kcp2KConnectionStates::Authenticated => Poll::Ready(Ok(())),
+                Kcp2KConnectionStates::Disconnected => Poll::Ready(Err(Kcp2KError::ConnectionClosed("Connection closed before authentication".to_string()))),
+                _ => {
+                    conn.register_connected_waker(cx.waker());
+                    Poll::Pending
+                }
+            },
+        })
+        .await
+    }
+
     pub fn connection(&self) -> &Arc<Option<Kcp2kConnection>> {
         &self.connection
     }
 
+    // 获取服务器在握手时分配的连接 ID，尚未认证或服务器未携带时返回 None
+    pub fn assigned_id(&self) -> Option<ConnId> {
+        self.connection.value().as_ref().and_then(|conn| conn.assigned_id())
+    }
+
+    // 设置借用形式的数据回调，设置后 OnData 事件改为通过该回调传递
+    pub fn set_data_callback(&self, data_callback: DataCallbackFuncType) {
+        self.kcp2k.data_callback_func.set_value(Some(data_callback.into()));
+    }
+
+    // 因超过 MTU 被丢弃的数据报数
+    pub fn oversized_datagrams(&self) -> u64 {
+        *self.kcp2k.oversized_datagrams
+    }
+
+    // 与上一次 tick 的间隔明显超过 KCP interval 的次数
+    pub fn slow_ticks(&self) -> u64 {
+        *self.kcp2k.slow_ticks
+    }
+
+    // socket 接收缓冲区当前的占用字节数，平台不支持时返回 None；持续接近缓冲区大小说明 tick 读取不及时
+    pub fn pending_os_recv_bytes(&self) -> Option<usize> {
+        pending_recv_bytes(&self.kcp2k.socket)
+    }
+
+    // 传入调用方从 socket 读取的数据报，用于 RecvStrategy::External
+    pub fn input(&self, sock_addr: &SockAddr, data: &[u8]) {
+        self.handle_data(sock_addr, data);
+    }
+
     pub fn send(&self, data: &[u8], channel: Kcp2KChannel) -> Result<(), Kcp2KError> {
         if let Some(conn) = self.connection.value_mut() {
             return conn.send_data(data, channel);
@@ -99,7 +220,59 @@ impl Kcp2KClient {
         Err(Kcp2KError::ConnectionClosed("Connection is closed".to_string()))
     }
 
+    // 重置客户端：关闭现有连接，并使用相同的配置与回调重新创建 socket，之后可以再次调用 connect()
+    // 新 socket 创建失败时返回错误，此时连接已经关闭，可以稍后再次调用 reset()
+    // 通过 from_socket 创建的客户端无法重新创建调用方的 socket，直接返回 Unsupported 错误且不做任何改动，应使用新的 socket 重新创建客户端
+    pub fn reset(&self) -> Result<(), Kcp2KError> {
+        if !self.owns_socket {
+            return Err(Kcp2KError::Io("reset() cannot recreate a socket passed to from_socket".to_string(), ErrorKind::Unsupported));
+        }
+        if let Some(conn) = self.connection.value()
+            && *conn.state != Kcp2KConnectionStates::Disconnected
+        {
+            conn.send_disconnect();
+        }
+        // 唤醒仍在等待 connected() 的任务，使其看到连接已关闭
+        if let Some(conn) = self.connection.value() {
+            conn.wake_connected();
+        }
+        self.connection.set_value(None);
+        self.local_addr.set_value(None);
+        self.pending_hello_addr.set_value(None);
+        self.kcp2k.socket.set_value(Kcp2K::create_socket(&self.kcp2k.config)?);
+        Ok(())
+    }
+
     pub fn stop(&self) -> Result<(), Error> {
         self.kcp2k.socket.shutdown(std::net::Shutdown::Both)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kcp2k_common::Callback;
+    use socket2::{Domain, Protocol, Type};
+
+    fn noop_callback(_: &Kcp2kConnection, _: Callback) {}
+
+    #[test]
+    fn reset_keeps_a_caller_supplied_socket() {
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP)).unwrap();
+        socket.bind(&"127.0.0.1:0".parse::<std::net::SocketAddr>().unwrap().into()).unwrap();
+        let local_addr = socket.local_addr().unwrap();
+        let client = Kcp2KClient::from_socket(socket, Kcp2KConfig::default(), Box::new(noop_callback)).unwrap();
+        assert!(client.connect("127.0.0.1:7777".to_string()).is_ok());
+
+        // 不替换调用方的 socket，也不关闭现有连接
+        assert!(matches!(client.reset(), Err(Kcp2KError::Io(_, ErrorKind::Unsupported))));
+        assert_eq!(client.kcp2k.socket.local_addr().unwrap(), local_addr);
+        assert!(client.connection.is_some());
+
+        // 客户端自己创建的 socket 可以重置
+        let client = Kcp2KClient::new(Kcp2KConfig::default(), Box::new(noop_callback)).unwrap();
+        assert!(client.connect("127.0.0.1:7777".to_string()).is_ok());
+        assert!(client.reset().is_ok());
+        assert!(client.connection.is_none());
+    }
+}
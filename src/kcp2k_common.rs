@@ -8,16 +8,21 @@ use revel_cell::arc::Arc;
 use socket2::{SockAddr, Socket};
 use std::fmt::{Display, Formatter};
 use std::hash::{DefaultHasher, Hash, Hasher};
-use std::io::Error;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::io::{Error, ErrorKind};
+use std::net::{SocketAddr, SocketAddrV6, ToSocketAddrs};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
 
 #[derive(Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 #[repr(u8)]
-pub(crate) enum Kcp2KConnectionStates {
+pub enum Kcp2KConnectionStates {
     None = 0,
     Authenticated = 1,
     Connected = 2,
     Disconnected = 3,
+    // 超时后进入断开宽限期，宽限期内收到数据包恢复为 Authenticated
+    Suspect = 4,
 }
 
 #[derive(Debug, PartialEq, Clone, Copy)]
@@ -27,47 +32,72 @@ pub(crate) enum Kcp2KReliableHeader {
     Hello = 1,
     Ping = 2,
     Data = 3,
+    Disconnect = 4,
+    Pong = 5,
+    DataWithReceipt = 6,
+    Receipt = 7,
+    Challenge = 8,
+    TaggedData = 9,
 }
-impl Into<u8> for Kcp2KReliableHeader {
-    fn into(self) -> u8 {
-        self as u8
+impl From<Kcp2KReliableHeader> for u8 {
+    fn from(value: Kcp2KReliableHeader) -> Self {
+        value as u8
     }
 }
-impl From<u8> for Kcp2KReliableHeader {
-    fn from(value: u8) -> Self {
+// 未定义的值返回错误，而不是回退到某个有效值：攻击者可能会发送超出枚举范围的值
+impl TryFrom<u8> for Kcp2KReliableHeader {
+    type Error = Kcp2KError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
         match value {
-            1 => Kcp2KReliableHeader::Hello,
-            2 => Kcp2KReliableHeader::Ping,
-            3 => Kcp2KReliableHeader::Data,
-            _ => Kcp2KReliableHeader::None,
+            0 => Ok(Kcp2KReliableHeader::None),
+            1 => Ok(Kcp2KReliableHeader::Hello),
+            2 => Ok(Kcp2KReliableHeader::Ping),
+            3 => Ok(Kcp2KReliableHeader::Data),
+            4 => Ok(Kcp2KReliableHeader::Disconnect),
+            5 => Ok(Kcp2KReliableHeader::Pong),
+            6 => Ok(Kcp2KReliableHeader::DataWithReceipt),
+            7 => Ok(Kcp2KReliableHeader::Receipt),
+            8 => Ok(Kcp2KReliableHeader::Challenge),
+            9 => Ok(Kcp2KReliableHeader::TaggedData),
+            _ => Err(Kcp2KError::InvalidReceive(format!("{} is not defined in Kcp2KReliableHeader", value))),
         }
     }
 }
 
 #[derive(Debug, PartialEq, Clone, Copy)]
 #[repr(u8)]
-pub(crate) enum Kcp2KUnreliableHeader {
+pub enum Kcp2KUnreliableHeader {
     Data = 4,
     Disconnect = 5,
     Ping = 6,
+    Pong = 7,
+    Batch = 8,
+    Reject = 9,
 }
-impl Into<u8> for Kcp2KUnreliableHeader {
-    fn into(self) -> u8 {
-        self as u8
+impl From<Kcp2KUnreliableHeader> for u8 {
+    fn from(value: Kcp2KUnreliableHeader) -> Self {
+        value as u8
     }
 }
-impl From<u8> for Kcp2KUnreliableHeader {
-    fn from(value: u8) -> Self {
+impl TryFrom<u8> for Kcp2KUnreliableHeader {
+    type Error = Kcp2KError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
         match value {
-            4 => Kcp2KUnreliableHeader::Data,
-            5 => Kcp2KUnreliableHeader::Disconnect,
-            6 => Kcp2KUnreliableHeader::Ping,
-            _ => Kcp2KUnreliableHeader::Disconnect,
+            4 => Ok(Kcp2KUnreliableHeader::Data),
+            5 => Ok(Kcp2KUnreliableHeader::Disconnect),
+            6 => Ok(Kcp2KUnreliableHeader::Ping),
+            7 => Ok(Kcp2KUnreliableHeader::Pong),
+            8 => Ok(Kcp2KUnreliableHeader::Batch),
+            9 => Ok(Kcp2KUnreliableHeader::Reject),
+            _ => Err(Kcp2KError::InvalidReceive(format!("{} is not defined in Kcp2KUnreliableHeader", value))),
         }
     }
 }
 
 #[derive(Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[repr(u8)]
 pub enum Kcp2KChannel {
     None = 0,
@@ -75,24 +105,26 @@ pub enum Kcp2KChannel {
     Unreliable = 2,
 }
 
-impl Into<u8> for Kcp2KChannel {
-    fn into(self) -> u8 {
-        self as u8
+impl From<Kcp2KChannel> for u8 {
+    fn from(value: Kcp2KChannel) -> Self {
+        value as u8
     }
 }
+impl TryFrom<u8> for Kcp2KChannel {
+    type Error = Kcp2KError;
 
-impl From<u8> for Kcp2KChannel {
-    fn from(value: u8) -> Self {
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
         match value {
-            1 => Kcp2KChannel::Reliable,
-            2 => Kcp2KChannel::Unreliable,
-            _ => Kcp2KChannel::Reliable,
+            0 => Ok(Kcp2KChannel::None),
+            1 => Ok(Kcp2KChannel::Reliable),
+            2 => Ok(Kcp2KChannel::Unreliable),
+            _ => Err(Kcp2KError::InvalidReceive(format!("{} is not defined in Kcp2KChannel", value))),
         }
     }
 }
 
 // 定义一个枚举来封装不同的错误类型。
-#[derive(Clone)]
+#[derive(Debug, Clone)]
 pub enum Kcp2KError {
     None(String),               // 无错误
     DnsResolve(String),         // 无法解析主机名
@@ -104,6 +136,9 @@ pub enum Kcp2KError {
     Unexpected(String),         // 意外错误/异常，需要修复。
     SendError(String),          // 发送数据失败
     ConnectionNotFound(String), // 未找到连接
+    InvalidConfig(String),      // 配置无效
+    MessageTooLarge(String),    // 系统报告数据报过大（EMSGSIZE），通常说明 MTU 设置过高
+    Io(String, ErrorKind),      // 底层 socket 的 I/O 错误，保留 ErrorKind 以便区分 WouldBlock 等可恢复的错误
 }
 
 impl Display for Kcp2KError {
@@ -119,16 +154,209 @@ impl Display for Kcp2KError {
             Kcp2KError::Unexpected(msg) => write!(f, "Unexpected: {}", msg),
             Kcp2KError::SendError(msg) => write!(f, "SendError: {}", msg),
             Kcp2KError::ConnectionNotFound(msg) => write!(f, "ConnectionNotFound: {}", msg),
+            Kcp2KError::InvalidConfig(msg) => write!(f, "InvalidConfig: {}", msg),
+            Kcp2KError::MessageTooLarge(msg) => write!(f, "MessageTooLarge: {}", msg),
+            Kcp2KError::Io(msg, kind) => write!(f, "Io({:?}): {}", kind, msg),
         }
     }
 }
 
+impl std::error::Error for Kcp2KError {}
+
+impl From<Error> for Kcp2KError {
+    fn from(err: Error) -> Self {
+        Kcp2KError::Io(err.to_string(), err.kind())
+    }
+}
+
+impl Kcp2KError {
+    // 带上下文说明的 I/O 错误，保留原始的 ErrorKind
+    pub(crate) fn io(context: &str, err: &Error) -> Self {
+        Kcp2KError::Io(format!("{}: {}", context, err), err.kind())
+    }
+}
+
 impl Default for Kcp2KError {
     fn default() -> Self {
         Kcp2KError::None("None".to_string())
     }
 }
-pub type CallbackFuncType = fn(&Kcp2kConnection, Callback);
+// 定义断开连接的原因，便于程序化处理（例如决定是否自动重连）
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum DisconnectReason {
+    None,           // 无
+    Timeout,        // 超时未收到数据
+    DeadLink,       // 超过最大重传次数
+    PeerRequested,  // 对端主动断开
+    Kicked,         // 被本端踢出
+    ServerShutdown, // 服务器关闭
+    ProtocolError,  // 协议错误
+    Rejected(u8),   // 服务器在接受连接时拒绝，附带拒绝原因代码
+}
+
+// 服务器接受过滤器的决定
+#[derive(Debug, PartialEq, Clone)]
+pub enum AcceptDecision {
+    Accept,
+    // 拒绝连接，向客户端发送一个拒绝数据报（原因代码 + 说明），不创建会话
+    Reject(u8, String),
+}
+
+// ConnId: 连接 ID，使用新类型包装避免与其他整数混用
+#[derive(Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ConnId(pub u64);
+
+impl Display for ConnId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl From<u64> for ConnId {
+    fn from(value: u64) -> Self {
+        ConnId(value)
+    }
+}
+
+// 回调可以捕获调用方的状态（例如 channel Sender、游戏世界句柄），由所有连接共享
+// 回调中可以调用 disconnect、send_data 等方法，这些方法可能再次同步触发回调，因此回调只能是 Fn，可变状态请自行放入 Mutex 或原子类型
+pub type CallbackFuncType = Box<dyn Fn(&Kcp2kConnection, Callback) + Send + Sync>;
+
+// 借用形式的数据回调，data 只在回调期间有效，避免 OnData 中的拷贝；最后一个参数为消息标签，普通消息为 0；与 CallbackFuncType 一样可以重入
+pub type DataCallbackFuncType = Box<dyn Fn(&Kcp2kConnection, ConnId, &[u8], Kcp2KChannel, u8) + Send + Sync>;
+
+// 连接内部持有的数据回调，调用前先克隆一份引用，回调中替换数据回调不会释放正在执行的闭包
+pub(crate) type SharedDataCallbackType = std::sync::Arc<dyn Fn(&Kcp2kConnection, ConnId, &[u8], Kcp2KChannel, u8) + Send + Sync>;
+
+pub type MetricsSinkType = Box<dyn FnMut(&ServerStats) + Send>;
+
+pub type PreTickOutgoingType = Box<dyn FnMut(&Kcp2kConnection) + Send>;
+
+pub type ConnIdAllocatorType = Box<dyn ConnIdAllocator + Send + Sync>;
+
+pub type AcceptFilterType = Box<dyn FnMut(&SockAddr) -> AcceptDecision + Send>;
+
+// ConnIdAllocator: 连接 ID 分配器，服务器为每个新连接调用一次
+pub trait ConnIdAllocator {
+    fn next(&self, addr: &SockAddr) -> ConnId;
+}
+
+// HashConnIdAllocator: 默认分配器，根据地址哈希生成连接 ID
+pub struct HashConnIdAllocator;
+
+impl ConnIdAllocator for HashConnIdAllocator {
+    fn next(&self, addr: &SockAddr) -> ConnId {
+        ConnId(connection_hash(addr))
+    }
+}
+
+// SequentialConnIdAllocator: 按 1, 2, 3, ... 递增分配连接 ID
+#[derive(Default)]
+pub struct SequentialConnIdAllocator {
+    last_id: AtomicU64,
+}
+
+impl ConnIdAllocator for SequentialConnIdAllocator {
+    fn next(&self, _addr: &SockAddr) -> ConnId {
+        ConnId(self.last_id.fetch_add(1, Ordering::Relaxed) + 1)
+    }
+}
+
+// ConnectionStats: 连接统计信息
+#[derive(Debug, Default, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ConnectionStats {
+    pub bytes_sent: u64,       // 发送的字节数
+    pub bytes_received: u64,   // 接收的字节数
+    pub packets_sent: u64,     // 发送的数据包数
+    pub packets_received: u64, // 接收的数据包数
+    pub send_would_block: u64, // 发送缓冲区已满（EWOULDBLOCK）导致排队重试的次数
+    pub lifetime_bytes_sent: u64,     // 连接建立以来发送的总字节数，take_stats 不会重置
+    pub lifetime_bytes_received: u64, // 连接建立以来接收的总字节数，take_stats 不会重置
+}
+
+impl ConnectionStats {
+    pub(crate) fn record_sent(&mut self, bytes: usize) {
+        self.bytes_sent += bytes as u64;
+        self.lifetime_bytes_sent += bytes as u64;
+        self.packets_sent += 1;
+    }
+
+    pub(crate) fn record_received(&mut self, bytes: usize) {
+        self.bytes_received += bytes as u64;
+        self.lifetime_bytes_received += bytes as u64;
+        self.packets_received += 1;
+    }
+
+    // 取出当前统计并清零可重置的计数，累计总量保持不变
+    pub(crate) fn take(&mut self) -> ConnectionStats {
+        let stats = *self;
+        *self = ConnectionStats {
+            lifetime_bytes_sent: self.lifetime_bytes_sent,
+            lifetime_bytes_received: self.lifetime_bytes_received,
+            ..Default::default()
+        };
+        stats
+    }
+}
+
+// ConnectionInfo: 连接信息快照，一次性汇总连接的各项属性
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ConnectionInfo {
+    pub conn_id: ConnId,
+    pub remote_address: String,
+    pub local_address: String,
+    pub state: Kcp2KConnectionStates,
+    pub cookie: u32,
+    pub reliable_mtu: usize,   // 可靠通道的有效 MTU
+    pub unreliable_mtu: usize, // 不可靠通道的有效 MTU
+    pub uptime: Duration,      // 连接创建至今的时长
+    pub stats: ConnectionStats,
+}
+
+// TickLatencyStats: 最近若干次 tick 的耗时分布
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TickLatencyStats {
+    pub samples: usize,
+    pub p50: Duration,
+    pub p95: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+impl TickLatencyStats {
+    pub(crate) fn from_samples(samples: impl Iterator<Item = Duration>) -> Self {
+        let mut sorted: Vec<Duration> = samples.collect();
+        if sorted.is_empty() {
+            return Self::default();
+        }
+        sorted.sort_unstable();
+        let percentile = |p: usize| sorted[((sorted.len() - 1) * p) / 100];
+        TickLatencyStats {
+            samples: sorted.len(),
+            p50: percentile(50),
+            p95: percentile(95),
+            p99: percentile(99),
+            max: sorted[sorted.len() - 1],
+        }
+    }
+}
+
+// ServerStats: 服务器统计信息，包含汇总数据与每个连接的快照
+#[derive(Debug, Default, Clone)]
+pub struct ServerStats {
+    pub connection_count: usize,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub packets_sent: u64,
+    pub packets_received: u64,
+    pub connections: Vec<(ConnId, ConnectionStats)>,
+    pub send_would_block: u64,
+    pub oversized_datagrams: u64, // 因超过 MTU 被丢弃的数据报数
+    pub slow_ticks: u64,          // 与上一次 tick 的间隔明显超过 KCP interval 的次数
+}
 
 #[derive(Debug)]
 pub enum CallbackType {
@@ -136,31 +364,56 @@ pub enum CallbackType {
     OnData,
     OnError,
     OnDisconnected,
+    OnRemoved,
+    OnReceipt,
+    OnChallenge,
+    OnSuspect,
+    OnResumed,
 }
 // Callback: 服务器回调
 pub struct Callback {
     pub r#type: CallbackType,
-    pub conn_id: u64,
+    pub conn_id: ConnId,
+    pub remote_addr: Option<SocketAddr>, // 对端地址，无法转换为 SocketAddr 时为 None
     pub channel: Kcp2KChannel,
     pub data: Vec<u8>,
     pub error: Kcp2KError,
+    pub disconnect_reason: DisconnectReason,
+    pub handshake_duration: Duration,
+    pub receipt_id: u32,
+    pub tag: u8,
 }
 
 impl Display for Callback {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self.r#type {
             CallbackType::OnConnected => {
-                write!(f, "OnConnected: id {} ", self.conn_id)
+                write!(f, "OnConnected: id {} handshake {:?}", self.conn_id, self.handshake_duration)
             }
             CallbackType::OnData => {
-                write!(f, "OnData: id {} {:?} {:?}", self.conn_id, self.channel, self.data.to_vec())
+                write!(f, "OnData: id {} {:?} tag {} {:?}", self.conn_id, self.channel, self.tag, self.data.to_vec())
             }
             CallbackType::OnDisconnected => {
-                write!(f, "OnDisconnected: id {}", self.conn_id)
+                write!(f, "OnDisconnected: id {} - {:?}", self.conn_id, self.disconnect_reason)
             }
             CallbackType::OnError => {
                 write!(f, "OnError: id {} - {}", self.conn_id, self.error)
             }
+            CallbackType::OnRemoved => {
+                write!(f, "OnRemoved: id {}", self.conn_id)
+            }
+            CallbackType::OnReceipt => {
+                write!(f, "OnReceipt: id {} receipt {}", self.conn_id, self.receipt_id)
+            }
+            CallbackType::OnChallenge => {
+                write!(f, "OnChallenge: id {} - {:?}", self.conn_id, self.data)
+            }
+            CallbackType::OnSuspect => {
+                write!(f, "OnSuspect: id {}", self.conn_id)
+            }
+            CallbackType::OnResumed => {
+                write!(f, "OnResumed: id {}", self.conn_id)
+            }
         }
     }
 }
@@ -169,10 +422,15 @@ impl Default for Callback {
     fn default() -> Self {
         Self {
             r#type: CallbackType::OnError,
-            conn_id: 0,
+            conn_id: ConnId::default(),
+            remote_addr: None,
             channel: Kcp2KChannel::None,
             data: Vec::new(),
             error: Kcp2KError::default(),
+            disconnect_reason: DisconnectReason::None,
+            handshake_duration: Duration::ZERO,
+            receipt_id: 0,
+            tag: 0,
         }
     }
 }
@@ -187,7 +445,9 @@ pub(crate) fn configure_socket_buffers(socket: &Socket, config: &Kcp2KConfig) ->
     socket.set_send_buffer_size(config.send_buffer_size)?;
 
     info!(
-        "[KCP2K] RecvBuf = {}=>{} ({}x) SendBuf = {}=>{} ({}x)",
+        instance = config.instance_name.as_deref();
+        "{} RecvBuf = {}=>{} ({}x) SendBuf = {}=>{} ({}x)",
+        config.log_prefix(),
         initial_receive,
         socket.recv_buffer_size()?,
         socket.recv_buffer_size()? / initial_receive,
@@ -198,6 +458,110 @@ pub(crate) fn configure_socket_buffers(socket: &Socket, config: &Kcp2KConfig) ->
     Ok(())
 }
 
+// 判断系统错误是否为数据报过大（EMSGSIZE / WSAEMSGSIZE）
+pub(crate) fn is_message_too_large(err: &Error) -> bool {
+    #[cfg(unix)]
+    const EMSGSIZE: i32 = libc::EMSGSIZE;
+    #[cfg(windows)]
+    const EMSGSIZE: i32 = 10040; // WSAEMSGSIZE
+    #[cfg(not(any(unix, windows)))]
+    const EMSGSIZE: i32 = -1;
+    err.raw_os_error() == Some(EMSGSIZE)
+}
+
+// 获取 socket 接收缓冲区当前的占用字节数，平台不支持时返回 None
+pub(crate) fn pending_recv_bytes(socket: &Socket) -> Option<usize> {
+    // Linux 上 UDP socket 的 FIONREAD 只返回下一个数据报的大小，因此读取 SO_MEMINFO 中接收队列占用的内存（包含内核的额外开销，
+    // 与 recv_buffer_size() 返回的加倍后的缓冲区大小可以直接比较）
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::fd::AsRawFd;
+        // libc 没有导出 SK_MEMINFO_VARS，内核中它紧跟在最后一项 SK_MEMINFO_DROPS 之后
+        const SK_MEMINFO_VARS: usize = libc::SK_MEMINFO_DROPS as usize + 1;
+        let mut meminfo = [0u32; SK_MEMINFO_VARS];
+        let mut len = size_of_val(&meminfo) as libc::socklen_t;
+        let result = unsafe { libc::getsockopt(socket.as_raw_fd(), libc::SOL_SOCKET, libc::SO_MEMINFO, meminfo.as_mut_ptr().cast(), &mut len) };
+        (result == 0).then_some(meminfo[libc::SK_MEMINFO_RMEM_ALLOC as usize] as usize)
+    }
+    #[cfg(all(unix, not(target_os = "linux")))]
+    {
+        use std::os::fd::AsRawFd;
+        let mut pending: libc::c_int = 0;
+        let result = unsafe { libc::ioctl(socket.as_raw_fd(), libc::FIONREAD, &mut pending) };
+        (result == 0).then_some(pending as usize)
+    }
+    #[cfg(not(unix))]
+    {
+        None
+    }
+}
+
+// ParsedFrame: 一个数据报的 kcp2k 帧结构，payload 借用自原始数据
+// 可靠通道的 header 在 KCP 分片内部，因此为 None，payload 为 KCP 分片
+// 不可靠通道的 payload 为 header 之后的数据（启用去重时包含 4 字节消息 ID）
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct ParsedFrame<'a> {
+    pub channel: Kcp2KChannel,
+    pub cookie: u32,
+    pub header: Option<Kcp2KUnreliableHeader>,
+    pub payload: &'a [u8],
+}
+
+// 写入不可靠消息的帧头部：通道 + cookie + header
+pub(crate) fn write_unreliable_frame_header(buffer: &mut Vec<u8>, cookie: u32, header: Kcp2KUnreliableHeader) {
+    // 写入通道头部
+    buffer.push(Kcp2KChannel::Unreliable.into());
+    // 写入握手 cookie 以防止 UDP 欺骗
+    buffer.extend_from_slice(&cookie.to_le_bytes());
+    // 写入 kcp 头部
+    buffer.push(header.into());
+}
+
+// 生成一个完整的不可靠消息数据报，可用于代理或中继改写数据包
+pub fn frame_unreliable(cookie: u32, header: Kcp2KUnreliableHeader, data: &[u8]) -> Vec<u8> {
+    let mut buffer = Vec::with_capacity(Kcp2KConfig::METADATA_SIZE_UNRELIABLE + 1 + data.len());
+    write_unreliable_frame_header(&mut buffer, cookie, header);
+    buffer.extend_from_slice(data);
+    buffer
+}
+
+// 解析数据报的通道、cookie 与 header，不拷贝数据
+pub fn parse_frame(bytes: &[u8]) -> Result<ParsedFrame<'_>, Kcp2KError> {
+    if bytes.len() <= Kcp2KConfig::COOKIE_HEADER_SIZE + Kcp2KConfig::CHANNEL_HEADER_SIZE {
+        return Err(Kcp2KError::InvalidReceive(format!("parse_frame: Received invalid message with length={}.", bytes.len())));
+    }
+    let cookie = u32::from_le_bytes([bytes[1], bytes[2], bytes[3], bytes[4]]);
+    let payload = &bytes[5..];
+    match Kcp2KChannel::try_from(bytes[0]) {
+        Ok(Kcp2KChannel::Reliable) => Ok(ParsedFrame { channel: Kcp2KChannel::Reliable, cookie, header: None, payload }),
+        Ok(Kcp2KChannel::Unreliable) => Ok(ParsedFrame {
+            channel: Kcp2KChannel::Unreliable,
+            cookie,
+            header: Some(Kcp2KUnreliableHeader::try_from(payload[0])?),
+            payload: &payload[1..],
+        }),
+        _ => Err(Kcp2KError::Unexpected(format!("parse_frame: Received message with unexpected channel={}.", bytes[0]))),
+    }
+}
+
+// 解析地址（IP 字面量或 "主机名:端口"），返回 create_socket 创建的 socket 可以使用的候选地址，保持解析器返回的顺序
+// dual_mode 下 socket 为 IPv6，IPv4 地址转换为 IPv4 映射的 IPv6 地址；否则只保留 IPv4 地址
+pub(crate) fn resolve_socket_addrs(addr: &str, config: &Kcp2KConfig) -> Result<Vec<SocketAddr>, Kcp2KError> {
+    let resolved = addr.to_socket_addrs().map_err(|e| Kcp2KError::DnsResolve(format!("{}: {}", addr, e)))?;
+    let candidates: Vec<SocketAddr> = resolved
+        .filter_map(|socket_addr| match (config.dual_mode, socket_addr) {
+            (true, SocketAddr::V4(v4)) => Some(SocketAddr::V6(SocketAddrV6::new(v4.ip().to_ipv6_mapped(), v4.port(), 0, 0))),
+            (true, SocketAddr::V6(_)) => Some(socket_addr),
+            (false, SocketAddr::V4(_)) => Some(socket_addr),
+            (false, SocketAddr::V6(_)) => None,
+        })
+        .collect();
+    if candidates.is_empty() {
+        return Err(Kcp2KError::DnsResolve(format!("{}: no usable addresses resolved (dual_mode: {})", addr, config.dual_mode)));
+    }
+    Ok(candidates)
+}
+
 // sock_addr hash
 pub(crate) fn connection_hash(sock_addr: &SockAddr) -> u64 {
     // cookie 与 sock_addr 一起生成一个唯一的连接 ID
@@ -206,20 +570,136 @@ pub(crate) fn connection_hash(sock_addr: &SockAddr) -> u64 {
     hasher.finish()
 }
 
-// 生成一个随机的 4 字节 cookie
+// 生成一个随机的 4 字节 cookie，使用操作系统提供的密码学安全随机数，使攻击者无法根据时间预测。
+// 系统随机源不可用时直接 panic：可以预测的 cookie 会让伪造源地址的数据包劫持连接，不能退回到时钟
 pub(crate) fn generate_cookie() -> u32 {
-    let start = SystemTime::now();
-    let since_the_epoch = start.duration_since(UNIX_EPOCH).expect("Time went backwards");
+    let mut bytes = [0u8; 4];
+    if let Err(e) = getrandom::fill(&mut bytes) {
+        panic!("failed to generate random cookie from the operating system: {}", e);
+    }
+    u32::from_le_bytes(bytes)
+}
 
-    let nanos = since_the_epoch.as_nanos(); // 获取纳秒级时间戳 (u128)
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    // 将 u128 纳秒时间戳转换为 u32，同时引入一些位操作增加“随机性”
-    // 目标是让结果在 u32 的 0 到 u32::MAX 范围内尽可能分散
-    // 这里的具体位操作可以根据需求调整，以最大化“混乱度”
-    let cookie_val = (nanos as u32)
-        ^ ((nanos >> 32) as u32) // 将 u128 的高位与低位异或
-        ^ ((nanos >> 64) as u32)
-        ^ ((nanos >> 96) as u32);
+    #[cfg(unix)]
+    #[test]
+    fn pending_recv_bytes_reflects_queued_datagrams() {
+        use std::net::SocketAddr;
+        let bind = || {
+            let socket = Socket::new(socket2::Domain::IPV4, socket2::Type::DGRAM, Some(socket2::Protocol::UDP)).unwrap();
+            let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+            socket.bind(&addr.into()).unwrap();
+            socket
+        };
+        let receiver = bind();
+        let sender = bind();
 
-    cookie_val
+        assert_eq!(pending_recv_bytes(&receiver), Some(0));
+        for _ in 0..4 {
+            sender.send_to(&[0u8; 100], &receiver.local_addr().unwrap()).unwrap();
+        }
+        assert!(pending_recv_bytes(&receiver).is_some_and(|bytes| bytes >= 400));
+    }
+
+    #[test]
+    fn resolve_socket_addrs_respects_dual_mode() {
+        let ipv4_only = Kcp2KConfig { dual_mode: false, ..Default::default() };
+        let dual = Kcp2KConfig { dual_mode: true, ..Default::default() };
+
+        assert_eq!(resolve_socket_addrs("127.0.0.1:3100", &ipv4_only).unwrap(), vec!["127.0.0.1:3100".parse::<SocketAddr>().unwrap()]);
+        assert_eq!(resolve_socket_addrs("127.0.0.1:3100", &dual).unwrap(), vec!["[::ffff:127.0.0.1]:3100".parse::<SocketAddr>().unwrap()]);
+        assert_eq!(resolve_socket_addrs("[::1]:3100", &dual).unwrap(), vec!["[::1]:3100".parse::<SocketAddr>().unwrap()]);
+        assert!(matches!(resolve_socket_addrs("[::1]:3100", &ipv4_only), Err(Kcp2KError::DnsResolve(_))));
+        assert!(matches!(resolve_socket_addrs("missing-port", &ipv4_only), Err(Kcp2KError::DnsResolve(_))));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn channel_round_trips_through_serde() {
+        let channel: Kcp2KChannel = serde_json::from_str(&serde_json::to_string(&Kcp2KChannel::Unreliable).unwrap()).unwrap();
+        assert_eq!(channel, Kcp2KChannel::Unreliable);
+    }
+
+    #[test]
+    fn io_errors_keep_their_kind() {
+        let err: Kcp2KError = Error::new(ErrorKind::WouldBlock, "try again").into();
+        assert!(matches!(err, Kcp2KError::Io(_, ErrorKind::WouldBlock)));
+
+        let boxed: Box<dyn std::error::Error> = Box::new(Kcp2KError::io("bind", &Error::from(ErrorKind::AddrInUse)));
+        assert!(boxed.to_string().starts_with("Io(AddrInUse): bind"));
+    }
+
+    #[test]
+    fn reliable_header_round_trips_every_byte() {
+        let mut defined = 0;
+        for value in 0..=u8::MAX {
+            if let Ok(header) = Kcp2KReliableHeader::try_from(value) {
+                assert_eq!(u8::from(header), value);
+                defined += 1;
+            }
+        }
+        assert_eq!(defined, 10);
+        assert!(Kcp2KReliableHeader::try_from(u8::from(Kcp2KReliableHeader::None)).ok() == Some(Kcp2KReliableHeader::None));
+        assert!(Kcp2KReliableHeader::try_from(10).is_err());
+    }
+
+    #[test]
+    fn unreliable_header_round_trips_every_byte() {
+        let mut defined = 0;
+        for value in 0..=u8::MAX {
+            if let Ok(header) = Kcp2KUnreliableHeader::try_from(value) {
+                assert_eq!(u8::from(header), value);
+                defined += 1;
+            }
+        }
+        assert_eq!(defined, 6);
+        // 未定义的值不再被当作 Disconnect
+        assert!(Kcp2KUnreliableHeader::try_from(0).is_err());
+        assert!(Kcp2KUnreliableHeader::try_from(10).is_err());
+    }
+
+    #[test]
+    fn channel_round_trips_every_byte() {
+        let mut defined = 0;
+        for value in 0..=u8::MAX {
+            if let Ok(channel) = Kcp2KChannel::try_from(value) {
+                assert_eq!(u8::from(channel), value);
+                defined += 1;
+            }
+        }
+        assert_eq!(defined, 3);
+        // 未定义的值不再被当作 Reliable
+        assert!(Kcp2KChannel::try_from(3).is_err());
+    }
+
+    #[test]
+    fn parse_frame_rejects_unknown_unreliable_header() {
+        assert!(matches!(parse_frame(&[2, 0, 0, 0, 0, 99]), Err(Kcp2KError::InvalidReceive(_))));
+    }
+
+    #[test]
+    fn unreliable_frame_round_trips() {
+        let bytes = frame_unreliable(0xDEADBEEF, Kcp2KUnreliableHeader::Data, b"hello");
+        assert_eq!(bytes[0], Kcp2KChannel::Unreliable as u8);
+        let frame = parse_frame(&bytes).ok().unwrap();
+        assert_eq!(frame.channel, Kcp2KChannel::Unreliable);
+        assert_eq!(frame.cookie, 0xDEADBEEF);
+        assert_eq!(frame.header, Some(Kcp2KUnreliableHeader::Data));
+        assert_eq!(frame.payload, b"hello");
+    }
+
+    #[test]
+    fn parse_frame_rejects_short_and_unknown_channel() {
+        assert!(matches!(parse_frame(&[1, 0, 0, 0, 0]), Err(Kcp2KError::InvalidReceive(_))));
+        assert!(matches!(parse_frame(&[9, 0, 0, 0, 0, 0]), Err(Kcp2KError::Unexpected(_))));
+
+        let frame = parse_frame(&[1, 1, 0, 0, 0, 42]).ok().unwrap();
+        assert_eq!(frame.channel, Kcp2KChannel::Reliable);
+        assert_eq!(frame.cookie, 1);
+        assert_eq!(frame.header, None);
+        assert_eq!(frame.payload, &[42]);
+    }
 }
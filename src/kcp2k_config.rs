@@ -1,6 +1,48 @@
 #![allow(unused)]
+
+use crate::kcp2k_common::Kcp2KError;
+use std::time::Duration;
+
+// 定义 RecvStrategy 枚举，用于控制每次 tick 从 socket 读取数据的方式
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum RecvStrategy {
+    // 每次 tick 一直读取，直到 socket 接收缓冲区为空
+    DrainAll,
+    // 每次 tick 最多读取指定数量的数据包
+    Bounded(usize),
+    // 每次 tick 最多花费指定的时间读取数据包
+    TimeBudget(Duration),
+    // tick 不从 socket 读取，由调用方自行读取后通过 input 传入（例如与其他协议共享同一个 socket）
+    External,
+}
+
+// 定义 SocketMode 枚举，用于控制 socket 的阻塞模式
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum SocketMode {
+    // 非阻塞：没有数据时读取立即返回，适合高频调用 tick 的循环
+    NonBlocking,
+    // 阻塞并设置读取超时：空闲时 tick 最多等待一次超时时间，适合低 CPU 占用的空闲服务器
+    BlockingWithTimeout(Duration),
+}
+
+// 定义 FaultConfig 结构体，通过 Kcp2KServer::set_fault_injection 让服务器故意出错，以测试客户端的错误处理（仅用于测试）
+#[cfg(any(test, feature = "testing"))]
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct FaultConfig {
+    // 每个发出的数据包使用不同的错误 cookie
+    pub corrupt_cookie: bool,
+    // 认证时不回复 Hello，客户端会一直等待直到超时
+    pub drop_hello_response: bool,
+    // 收到 Hello 后延迟指定时间再认证
+    pub delay_authentication: Option<Duration>,
+}
+
 // 定义 KcpConfig 结构体，用于配置 KCP 服务器
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
 pub struct Kcp2KConfig {
     // 使用 IPv6 和 IPv4 的双模式，不是所有平台都支持
     pub dual_mode: bool,
@@ -26,6 +68,48 @@ pub struct Kcp2KConfig {
     pub max_retransmits: u32,
     // 是否启用可靠的 ping 功能
     pub is_reliable_ping: bool,
+    // 接收策略，在延迟与公平性之间权衡：全部读取可能占用过长时间，读取过少则可能导致内核丢包
+    pub recv_strategy: RecvStrategy,
+    // 不可靠通道去重：每条不可靠消息携带消息 ID，接收端丢弃滑动窗口内重复的消息（不保证顺序与送达）
+    pub unreliable_dedup: bool,
+    // 可靠断开：通过可靠通道发送断开消息，等待对端确认（或链路失效、超过等待时间）后再断开
+    pub reliable_disconnect: bool,
+    // 每个连接的出站带宽上限（比特每秒），超出时可靠数据留在 KCP 队列中等待，不可靠数据被丢弃
+    pub max_out_bps_per_conn: Option<u64>,
+    // socket 阻塞模式。阻塞模式下 tick 在没有数据时会等待读取超时，每个绑定的 socket 各等待一次，同时也会推迟 KCP 的刷新
+    pub socket_mode: SocketMode,
+    // 实例名称，在同一进程中运行多个服务器/客户端时添加到日志前缀和日志的 instance 字段中以便区分，为 None 时不添加
+    pub instance_name: Option<String>,
+    // 每次发送可靠数据后立即刷新 KCP，而不是等待下一个 interval。
+    // 降低低频消息的延迟，但每条消息都会单独成包，增加 CPU 与带宽开销（无法合并多条消息）
+    pub flush_on_send: bool,
+    // 可靠消息回执：启用后可以通过 send_reliable_with_receipt 发送带回执的消息，对端交给应用层后回复回执（对端需要支持回执）
+    pub reliable_receipts: bool,
+    // 服务器连接在认证之前最多接收的可靠分段数，超过则断开，防止半开连接占用接收队列内存
+    pub max_preauth_segments: usize,
+    // 合并同一个 tick 内发往同一连接的不可靠消息，在 tick_outgoing 中作为一个数据报发送（对端需要支持合并格式）
+    pub coalesce_unreliable: bool,
+    // 在每条可靠消息中也写入并校验 cookie，作为外层 cookie 之外的纵深防御（两端需一致）
+    pub strict_cookie_on_reliable: bool,
+    // 自动 ping：关闭后不再定期发送 ping，连接的存活完全依赖应用层流量；应用层超过 timeout 没有发送任何数据时对端会判定超时
+    pub auto_ping: bool,
+    // 拥塞降级：可靠队列持续积压时，通过 send_latest_state 发送的消息改走不可靠通道，只保证最新状态尽快送达
+    pub downgrade_under_congestion: bool,
+    // 断开宽限期（毫秒）：超时后先进入 Suspect 状态并触发 OnSuspect，宽限期内没有收到任何数据包才触发 OnDisconnected，为 0 时超时立即断开
+    pub disconnect_grace_ms: u64,
+    // 设置后每个连接按该间隔在 tick 中输出一行吞吐统计日志（conn_id、收发 bps、rtt、不可靠通道丢包率）
+    pub log_stats_interval: Option<Duration>,
+    // 对来自已认证对端（cookie 正确）的无效数据保持宽容：只报告 InvalidReceive 错误并丢弃该消息，不断开连接
+    pub lenient_invalid_receive: bool,
+    // 未知头部容忍模式：cookie 正确但头部未定义的可靠/不可靠消息计数后忽略，超过该数量才断开，便于不同协议版本的对端共存。
+    // 为 None 时未知的可靠头部按错误策略处理，未知的不可靠头部只报告
+    pub unknown_header_budget: Option<u32>,
+    // 每个连接每秒最多交付的应用消息数（可靠与不可靠合计），超出的消息被丢弃，防止应用层消息洪泛
+    pub max_messages_per_sec_per_conn: Option<u32>,
+    // 持续超出消息速率上限（RATE_ABUSE_DURATION）时断开连接
+    pub disconnect_on_rate_abuse: bool,
+    // 服务器最多同时保持的连接数，达到上限后来自新地址的数据包被丢弃，已有连接不受影响；为 0 时不限制
+    pub max_connections: usize,
 }
 
 impl Kcp2KConfig {
@@ -34,6 +118,278 @@ impl Kcp2KConfig {
     pub const COOKIE_HEADER_SIZE: usize = 4;
     pub const METADATA_SIZE_RELIABLE: usize = Self::CHANNEL_HEADER_SIZE + Self::COOKIE_HEADER_SIZE;
     pub const METADATA_SIZE_UNRELIABLE: usize = Self::CHANNEL_HEADER_SIZE + Self::COOKIE_HEADER_SIZE;
+    pub const UNRELIABLE_MESSAGE_ID_SIZE: usize = 4;
+    pub const UNRELIABLE_DEDUP_WINDOW: usize = 256;
+    pub const RELIABLE_DISCONNECT_WAIT: u64 = 1000;
+    pub const SEND_RETRY_QUEUE_LIMIT: usize = 64;
+    // 服务器在对端确认认证之前最多暂存的不可靠消息数
+    pub const DEFERRED_UNRELIABLE_LIMIT: usize = 64;
+    // 统计 tick 耗时分布时保留的最近样本数
+    pub const TICK_LATENCY_SAMPLES: usize = 1024;
+    // 认证前的 KCP 发送窗口大小；KCP 的接收窗口不能小于 128，认证前的接收缓存由 max_preauth_segments 限制
+    pub const PRE_AUTH_WINDOW_SIZE: u16 = 8;
+    // 服务器移除连接后，忽略来自该地址的数据包的宽限期（毫秒），避免在途数据包重新创建连接
+    pub const RECENTLY_REMOVED_GRACE: u64 = 1000;
+    // 服务器等待迁移质询回复的时间（毫秒）
+    pub const PATH_CHALLENGE_TIMEOUT: u64 = 1000;
+    // tick 间隔超过 interval 的多少倍时视为 tick 过慢
+    pub const SLOW_TICK_FACTOR: u64 = 2;
+    // tick 过慢警告的最小间隔（毫秒）
+    pub const SLOW_TICK_WARNING_INTERVAL: u64 = 5000;
+    // 合并的不可靠消息中，每条消息前的长度前缀大小
+    pub const UNRELIABLE_BATCH_LENGTH_SIZE: usize = 2;
+    // 可靠队列中等待发送的分段数超过发送窗口的多少倍时视为积压
+    pub const DOWNGRADE_QUEUE_FACTOR: usize = 2;
+    // 积压持续多久（毫秒）后开始降级
+    pub const DOWNGRADE_SUSTAIN: u64 = 500;
+    // 持续超出接收消息速率上限多久（毫秒）视为滥用，两次丢弃间隔超过一秒时重新计时
+    pub const RATE_ABUSE_DURATION: u64 = 5000;
+    // KCP 时钟（毫秒，约 12.4 天）超过该值后，在没有未确认分段时从 0 重新计时
+    pub const KCP_CLOCK_REBASE: u32 = 1 << 30;
+    // KCP 时钟的上限（毫秒），为 KCP 计时器的加法留出余量，使其不超过 i32::MAX；到达上限的连接按失效链接断开
+    pub const KCP_CLOCK_LIMIT: u32 = (1 << 31) - (1 << 28);
+
+    // 可靠通道的有效 MTU：交给 KCP 的 MTU，需要为通道头部和 cookie 预留空间（KCP 头部由 KCP 自己扣除）
+    pub fn reliable_mtu(&self) -> usize {
+        self.mtu.saturating_sub(Self::METADATA_SIZE_RELIABLE)
+    }
+
+    // 不可靠通道的有效 MTU：不经过 KCP，只需要为通道头部和 cookie 预留空间
+    pub fn unreliable_mtu(&self) -> usize {
+        self.mtu.saturating_sub(Self::METADATA_SIZE_UNRELIABLE)
+    }
+
+    // 日志前缀，设置了实例名称时附加在 [KCP2K] 之后
+    pub(crate) fn log_prefix(&self) -> String {
+        match self.instance_name.as_deref() {
+            None => "[KCP2K]".to_string(),
+            Some(instance_name) => format!("[KCP2K][{}]", instance_name),
+        }
+    }
+
+    // 接收路径上的错误是否需要断开连接，所有由对端数据引起的错误都通过这里决定：
+    // - Timeout、ConnectionClosed、Unexpected 总是致命的
+    // - InvalidReceive 默认致命，启用 lenient_invalid_receive 后只报告不断开
+    // - 其余错误（发送失败、拥塞、配置错误等）由本端引起，不会因此断开连接
+    // 数据报的帧格式错误发生在 cookie 校验之前，无法确认来自对端，因此只报告，不经过该策略
+    pub fn should_disconnect(&self, err: &Kcp2KError) -> bool {
+        match err {
+            Kcp2KError::Timeout(_) | Kcp2KError::ConnectionClosed(_) | Kcp2KError::Unexpected(_) => true,
+            Kcp2KError::InvalidReceive(_) => !self.lenient_invalid_receive,
+            _ => false,
+        }
+    }
+
+    // 校验配置是否合法，不依赖 socket，可在加载配置文件后单独调用
+    pub fn validate(&self) -> Result<(), Kcp2KError> {
+        // kcp 要求 mtu 不小于 50 且大于 KCP_OVERHEAD，且需要为通道头部和 cookie 预留空间
+        let kcp_mtu = self.mtu.saturating_sub(Self::METADATA_SIZE_RELIABLE);
+        if kcp_mtu < 50 || kcp_mtu <= kcp::KCP_OVERHEAD {
+            return Err(Kcp2KError::InvalidConfig(format!("mtu={} is too small, kcp needs at least 50 bytes after {} bytes of metadata", self.mtu, Self::METADATA_SIZE_RELIABLE)));
+        }
+        if self.recv_buffer_size == 0 || self.send_buffer_size == 0 {
+            return Err(Kcp2KError::InvalidConfig(format!("socket buffer sizes must be positive: recv={} send={}", self.recv_buffer_size, self.send_buffer_size)));
+        }
+        if self.interval <= 0 {
+            return Err(Kcp2KError::InvalidConfig(format!("interval={} must be positive", self.interval)));
+        }
+        if self.fast_resend < 0 {
+            return Err(Kcp2KError::InvalidConfig(format!("fast_resend={} must not be negative", self.fast_resend)));
+        }
+        if self.send_window_size == 0 || self.receive_window_size == 0 {
+            return Err(Kcp2KError::InvalidConfig(format!("window sizes must be positive: send={} receive={}", self.send_window_size, self.receive_window_size)));
+        }
+        if self.timeout <= Self::PING_INTERVAL {
+            return Err(Kcp2KError::InvalidConfig(format!("timeout={}ms must be greater than the ping interval {}ms", self.timeout, Self::PING_INTERVAL)));
+        }
+        if self.max_retransmits == 0 {
+            return Err(Kcp2KError::InvalidConfig("max_retransmits must be positive".to_string()));
+        }
+        if self.max_preauth_segments == 0 {
+            return Err(Kcp2KError::InvalidConfig("max_preauth_segments must be positive, the Hello needs at least one segment".to_string()));
+        }
+        if self.log_stats_interval.is_some_and(|interval| interval.is_zero()) {
+            return Err(Kcp2KError::InvalidConfig("log_stats_interval must be positive".to_string()));
+        }
+        if self.max_messages_per_sec_per_conn == Some(0) {
+            return Err(Kcp2KError::InvalidConfig("max_messages_per_sec_per_conn must be positive".to_string()));
+        }
+        if self.max_out_bps_per_conn == Some(0) {
+            return Err(Kcp2KError::InvalidConfig("max_out_bps_per_conn must be positive".to_string()));
+        }
+        if let SocketMode::BlockingWithTimeout(timeout) = self.socket_mode
+            && timeout.is_zero()
+        {
+            return Err(Kcp2KError::InvalidConfig("socket_mode BlockingWithTimeout needs a positive timeout".to_string()));
+        }
+        match self.recv_strategy {
+            RecvStrategy::Bounded(0) => Err(Kcp2KError::InvalidConfig("recv_strategy Bounded(0) would never read from the socket".to_string())),
+            RecvStrategy::TimeBudget(budget) if budget.is_zero() => Err(Kcp2KError::InvalidConfig("recv_strategy TimeBudget must be positive".to_string())),
+            _ => Ok(()),
+        }
+    }
+}
+
+// 定义 CSharpKcpConfig 结构体，字段、类型与默认值与 C# kcp2k 的 KcpConfig 一致，用于从 C# 迁移
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default, rename_all = "PascalCase"))]
+pub struct CSharpKcpConfig {
+    pub dual_mode: bool,
+    pub recv_buffer_size: i32,
+    pub send_buffer_size: i32,
+    pub mtu: i32,
+    pub no_delay: bool,
+    pub interval: u32,
+    pub fast_resend: i32,
+    pub congestion_window: bool,
+    pub send_window_size: u32,
+    pub receive_window_size: u32,
+    pub timeout: i32,
+    pub max_retransmits: u32,
+}
+
+impl Default for CSharpKcpConfig {
+    // 与 C# KcpConfig 构造函数的默认参数相同
+    fn default() -> Self {
+        CSharpKcpConfig {
+            dual_mode: true,
+            recv_buffer_size: 1024 * 1024 * 7,
+            send_buffer_size: 1024 * 1024 * 7,
+            mtu: 1200,                // Kcp.MTU_DEF
+            no_delay: true,
+            interval: 10,
+            fast_resend: 0,
+            congestion_window: false,
+            send_window_size: 32,     // Kcp.WND_SND
+            receive_window_size: 128, // Kcp.WND_RCV
+            timeout: 10000,           // KcpPeer.DEFAULT_TIMEOUT
+            max_retransmits: 20,      // Kcp.DEADLINK
+        }
+    }
+}
+
+impl From<CSharpKcpConfig> for Kcp2KConfig {
+    // C# 中没有对应字段的选项使用 Rust 的默认值。C# 的 ping 总是通过可靠通道发送，与 is_reliable_ping 的默认值一致；
+    // 超出范围的值（负数缓冲区、超过 u16 的窗口等）会被截断到有效范围，之后仍由 validate 校验
+    fn from(config: CSharpKcpConfig) -> Self {
+        Kcp2KConfig {
+            dual_mode: config.dual_mode,
+            recv_buffer_size: config.recv_buffer_size.max(0) as usize,
+            send_buffer_size: config.send_buffer_size.max(0) as usize,
+            mtu: config.mtu.max(0) as usize,
+            no_delay: config.no_delay,
+            interval: config.interval.min(i32::MAX as u32) as i32,
+            fast_resend: config.fast_resend,
+            // 两边的含义相同：true 表示启用拥塞控制，创建 KCP 时都会取反作为 nocwnd
+            congestion_window: config.congestion_window,
+            send_window_size: config.send_window_size.min(u16::MAX as u32) as u16,
+            receive_window_size: config.receive_window_size.min(u16::MAX as u32) as u16,
+            timeout: config.timeout.max(0) as u64,
+            max_retransmits: config.max_retransmits,
+            is_reliable_ping: true,
+            ..Default::default()
+        }
+    }
+}
+
+impl Kcp2KConfig {
+    // 使用与 C# kcp2k KcpConfig 相同的默认值创建配置
+    pub fn from_csharp_defaults() -> Self {
+        CSharpKcpConfig::default().into()
+    }
+
+    // 从默认配置开始构建
+    pub fn builder() -> Kcp2KConfigBuilder {
+        Kcp2KConfigBuilder::default()
+    }
+}
+
+// Kcp2KConfigBuilder: 以链式调用设置常用字段，build() 时校验配置，未设置的字段使用 Kcp2KConfig::default() 的值
+#[derive(Debug, Clone, Default)]
+pub struct Kcp2KConfigBuilder {
+    config: Kcp2KConfig,
+}
+
+impl Kcp2KConfigBuilder {
+    pub fn dual_mode(mut self, dual_mode: bool) -> Self {
+        self.config.dual_mode = dual_mode;
+        self
+    }
+
+    pub fn buffer_sizes(mut self, recv_buffer_size: usize, send_buffer_size: usize) -> Self {
+        self.config.recv_buffer_size = recv_buffer_size;
+        self.config.send_buffer_size = send_buffer_size;
+        self
+    }
+
+    pub fn mtu(mut self, mtu: usize) -> Self {
+        self.config.mtu = mtu;
+        self
+    }
+
+    pub fn no_delay(mut self, no_delay: bool) -> Self {
+        self.config.no_delay = no_delay;
+        self
+    }
+
+    pub fn interval(mut self, interval: i32) -> Self {
+        self.config.interval = interval;
+        self
+    }
+
+    pub fn fast_resend(mut self, fast_resend: i32) -> Self {
+        self.config.fast_resend = fast_resend;
+        self
+    }
+
+    pub fn congestion_window(mut self, congestion_window: bool) -> Self {
+        self.config.congestion_window = congestion_window;
+        self
+    }
+
+    pub fn window_sizes(mut self, send_window_size: u16, receive_window_size: u16) -> Self {
+        self.config.send_window_size = send_window_size;
+        self.config.receive_window_size = receive_window_size;
+        self
+    }
+
+    // 超时，单位为毫秒
+    pub fn timeout(mut self, timeout: u64) -> Self {
+        self.config.timeout = timeout;
+        self
+    }
+
+    pub fn max_retransmits(mut self, max_retransmits: u32) -> Self {
+        self.config.max_retransmits = max_retransmits;
+        self
+    }
+
+    pub fn is_reliable_ping(mut self, is_reliable_ping: bool) -> Self {
+        self.config.is_reliable_ping = is_reliable_ping;
+        self
+    }
+
+    pub fn recv_strategy(mut self, recv_strategy: RecvStrategy) -> Self {
+        self.config.recv_strategy = recv_strategy;
+        self
+    }
+
+    pub fn socket_mode(mut self, socket_mode: SocketMode) -> Self {
+        self.config.socket_mode = socket_mode;
+        self
+    }
+
+    pub fn instance_name(mut self, instance_name: impl Into<String>) -> Self {
+        self.config.instance_name = Some(instance_name.into());
+        self
+    }
+
+    // 校验后返回配置，规则与 Kcp2KConfig::validate 相同
+    pub fn build(self) -> Result<Kcp2KConfig, Kcp2KError> {
+        self.config.validate()?;
+        Ok(self.config)
+    }
 }
 
 impl Default for Kcp2KConfig {
@@ -53,6 +409,68 @@ impl Default for Kcp2KConfig {
             timeout: 2000,            // 默认的超时时间
             max_retransmits: 20,      // 默认的最大重传次数
             is_reliable_ping: true,   // 默认的可靠 ping
+            recv_strategy: RecvStrategy::DrainAll, // 默认每次 tick 读取全部数据
+            unreliable_dedup: false,               // 默认不对不可靠消息去重
+            reliable_disconnect: false,            // 默认通过不可靠通道发送断开通知
+            max_out_bps_per_conn: None,            // 默认不限制出站带宽
+            socket_mode: SocketMode::NonBlocking,  // 默认非阻塞
+            instance_name: None,                   // 默认不设置实例名称
+            flush_on_send: false,                  // 默认等待 interval 统一刷新
+            reliable_receipts: false,              // 默认不使用回执，保持线路格式兼容
+            max_preauth_segments: 16,              // 认证前只需要 Hello 与少量 ping
+            coalesce_unreliable: false,            // 默认每条不可靠消息单独发送
+            strict_cookie_on_reliable: false,      // 默认只校验数据报外层的 cookie
+            auto_ping: true,                       // 默认由传输层维持连接存活
+            downgrade_under_congestion: false,     // 默认 send_latest_state 始终走可靠通道
+            disconnect_grace_ms: 0,                // 默认超时立即断开
+            log_stats_interval: None,              // 默认不输出统计日志
+            lenient_invalid_receive: false,        // 默认收到无效数据即断开
+            unknown_header_budget: None,           // 默认不启用容忍模式
+            max_messages_per_sec_per_conn: None,   // 默认不限制接收的消息速率
+            disconnect_on_rate_abuse: false,       // 默认只丢弃超出的消息
+            max_connections: 0,                    // 默认不限制连接数
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn effective_mtus_saturate_below_the_metadata_size() {
+        let config = Kcp2KConfig { mtu: 3, ..Default::default() };
+        assert_eq!(config.reliable_mtu(), 0);
+        assert_eq!(config.unreliable_mtu(), 0);
+        assert!(config.validate().is_err());
+
+        let config = Kcp2KConfig::default();
+        assert_eq!(config.reliable_mtu(), config.mtu - Kcp2KConfig::METADATA_SIZE_RELIABLE);
+        assert_eq!(config.unreliable_mtu(), config.mtu - Kcp2KConfig::METADATA_SIZE_UNRELIABLE);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn config_round_trips_through_serde() {
+        let config = Kcp2KConfig {
+            dual_mode: true,
+            mtu: 1400,
+            interval: 5,
+            send_window_size: 256,
+            recv_strategy: RecvStrategy::Bounded(64),
+            log_stats_interval: Some(Duration::from_secs(10)),
+            ..Default::default()
+        };
+        let decoded: Kcp2KConfig = serde_json::from_str(&serde_json::to_string(&config).unwrap()).unwrap();
+        assert_eq!(decoded, config);
+
+        // 实例名称同样参与序列化
+        let named = Kcp2KConfig { instance_name: Some("lobby".to_string()), ..config };
+        let decoded: Kcp2KConfig = serde_json::from_str(&serde_json::to_string(&named).unwrap()).unwrap();
+        assert_eq!(decoded, named);
+
+        // 缺失的字段使用默认值
+        let partial: Kcp2KConfig = serde_json::from_str(r#"{"mtu": 1300}"#).unwrap();
+        assert_eq!(partial, Kcp2KConfig { mtu: 1300, ..Default::default() });
+    }
+}
@@ -1,67 +1,171 @@
 use crate::kcp2k::Kcp2KMode;
-use crate::kcp2k_common::{generate_cookie, Callback, CallbackFuncType, CallbackType, Kcp2KChannel, Kcp2KConnectionStates, Kcp2KError, Kcp2KReliableHeader, Kcp2KUnreliableHeader};
+use crate::kcp2k_common::{frame_unreliable, generate_cookie, is_message_too_large, Callback, CallbackFuncType, CallbackType, ConnId, ConnectionInfo, ConnectionStats, DisconnectReason, Kcp2KChannel, Kcp2KConnectionStates, Kcp2KError, Kcp2KReliableHeader, Kcp2KUnreliableHeader, parse_frame, write_unreliable_frame_header, SharedDataCallbackType};
 use crate::kcp2k_config::Kcp2KConfig;
+#[cfg(any(test, feature = "testing"))]
+use crate::kcp2k_config::FaultConfig;
 use kcp::Kcp;
+use log::{info, warn};
 use revel_cell::arc::Arc;
 use socket2::{SockAddr, Socket};
+use std::collections::VecDeque;
+use std::any::Any;
 use std::io;
 use std::io::Write;
+use std::net::SocketAddr;
+use std::task::Waker;
 use std::time::{Duration, Instant};
 
+// KCP 分段中的 ACK 命令字
+const KCP_CMD_PUSH: u8 = 81;
+const KCP_CMD_ACK: u8 = 82;
+
 #[allow(unused)]
 pub struct Kcp2kConnection {
-    id: u64,
+    id: ConnId,
     config: Arc<Kcp2KConfig>,
     kcp2k_mode: Arc<Kcp2KMode>,
-    callback_func: CallbackFuncType,
+    callback_func: Arc<CallbackFuncType>,
+    data_callback_func: Arc<Option<SharedDataCallbackType>>,
     cookie: Arc<u32>,
     pub(crate) state: Arc<Kcp2KConnectionStates>,
-    socket: Arc<Socket>,
+    socket: Arc<Arc<Socket>>,
     client_sock_addr: Arc<SockAddr>,
     kcp: Arc<Kcp<UdpOutput>>,
     watch: Instant,
+    kcp_epoch: Arc<Duration>,
     last_send_ping_time: Arc<Duration>,
     last_recv_time: Arc<Duration>,
+    send_buffer: Arc<Vec<u8>>,
+    stats: Arc<ConnectionStats>,
+    unreliable_message_id: Arc<u32>,
+    unreliable_seen: Arc<VecDeque<u32>>,
+    unreliable_id_range: Arc<Option<(u32, u32)>>,
+    unreliable_received_ids: Arc<u64>,
+    rx_srtt: Arc<u32>,
+    rx_rttval: Arc<u32>,
+    peer_rtt: Arc<Option<u32>>,
+    disconnect_deadline: Arc<Option<Duration>>,
+    last_send_error: Arc<Option<SendErrorInfo>>,
+    connected_waker: Arc<Option<Waker>>,
+    out_tokens: Arc<f64>,
+    out_tokens_time: Arc<Duration>,
+    last_ping_rtt: Arc<Option<Duration>>,
+    fault: FaultInjection,
+    #[cfg(any(test, feature = "testing"))]
+    pending_authentication: Arc<Option<Duration>>,
+    #[cfg(feature = "debug-capture")]
+    last_raw_input: Arc<Option<Vec<u8>>>,
+    receipt_message_id: Arc<u32>,
+    send_retry_queue: Arc<VecDeque<Vec<u8>>>,
+    max_retransmits: Arc<u32>,
+    assigned_id: Arc<Option<ConnId>>,
+    reliable_segments: Arc<u32>,
+    hello_sn: Arc<Option<u32>>,
+    peer_confirmed: Arc<bool>,
+    deferred_unreliable: Arc<Vec<Vec<u8>>>,
+    unacked_messages: Arc<VecDeque<(u32, usize)>>,
+    acked_bytes: Arc<u64>,
+    preauth_segments: Arc<usize>,
+    unreliable_batch: Arc<Vec<u8>>,
+    recovery_point: Arc<Option<u32>>,
+    muted: Arc<bool>,
+    congested_since: Arc<Option<Duration>>,
+    congested: Arc<bool>,
+    stats_log_mark: Arc<(Duration, u64, u64)>,
+    unknown_headers: Arc<u32>,
+    in_tokens: Arc<f64>,
+    in_tokens_time: Arc<Duration>,
+    rate_abuse: Arc<Option<(Duration, Duration)>>,
+    rate_limited_messages: Arc<u64>,
+    user_data: Arc<Option<Box<dyn Any + Send>>>,
+}
+
+// 最近一次发送失败时系统报告的错误
+#[derive(Debug, Clone, Copy)]
+struct SendErrorInfo {
+    kind: io::ErrorKind,
+    message_too_large: bool,
+}
+
+impl SendErrorInfo {
+    fn record(cell: &Arc<Option<SendErrorInfo>>, err: &io::Error) {
+        cell.set_value(Some(SendErrorInfo { kind: err.kind(), message_too_large: is_message_too_large(err) }));
+    }
 }
 
 #[derive(Debug)]
 pub struct UdpOutput {
     kcp2k_mode: Arc<Kcp2KMode>,      // kcp2k_mode
     cookie: Arc<u32>,                // cookie
-    socket: Arc<Socket>,             // socket
-    client_sock_addr: Arc<SockAddr>, // client_sock_addr
+    socket: Arc<Arc<Socket>>,        // socket，迁移时替换为接收新地址数据包的 socket
+    client_sock_addr: Arc<SockAddr>, // client_sock_addr，与连接共享同一个单元
+    stats: Arc<ConnectionStats>,     // 连接统计信息
+    last_send_error: Arc<Option<SendErrorInfo>>, // 最近一次发送错误，与连接共享
+    buffer: Vec<u8>,                 // 复用的发送缓冲区
+    next_sn: u32,                    // 下一个首次发送的分段序号，小于它的分段为重传
+    recovery_point: Arc<Option<u32>>, // 进入恢复时已发送的最高序号，与连接共享
+    fault: FaultInjection,           // 故障注入，与连接共享
 }
 impl UdpOutput {
     // 创建一个新的 Writer，用于将数据包写入 UdpSocket
-    fn new(kcp2k_mode: Arc<Kcp2KMode>, cookie: Arc<u32>, socket: Arc<Socket>, client_sock_addr: Arc<SockAddr>) -> UdpOutput {
-        UdpOutput { kcp2k_mode, cookie, socket, client_sock_addr }
+    #[allow(clippy::too_many_arguments)]
+    fn new(kcp2k_mode: Arc<Kcp2KMode>, cookie: Arc<u32>, socket: Arc<Arc<Socket>>, client_sock_addr: Arc<SockAddr>, stats: Arc<ConnectionStats>, last_send_error: Arc<Option<SendErrorInfo>>, recovery_point: Arc<Option<u32>>, fault: FaultInjection) -> UdpOutput {
+        UdpOutput { kcp2k_mode, cookie, socket, client_sock_addr, stats, last_send_error, buffer: Vec::new(), next_sn: 0, recovery_point, fault }
+    }
+
+    // 检查发出的数据分段，发现重传时进入恢复状态，直到对端确认进入恢复时已发送的全部分段
+    fn inspect_outgoing(&mut self, data: &[u8]) {
+        let mut offset = 0;
+        // 分段头部: conv(4) cmd(1) frg(1) wnd(2) ts(4) sn(4) una(4) len(4)
+        while offset + kcp::KCP_OVERHEAD <= data.len() {
+            let segment = &data[offset..];
+            let sn = u32::from_le_bytes([segment[12], segment[13], segment[14], segment[15]]);
+            let len = u32::from_le_bytes([segment[20], segment[21], segment[22], segment[23]]) as usize;
+            if segment[4] == KCP_CMD_PUSH {
+                if (sn.wrapping_sub(self.next_sn) as i32) < 0 {
+                    if self.recovery_point.is_none() {
+                        self.recovery_point.set_value(Some(self.next_sn));
+                    }
+                } else {
+                    self.next_sn = sn.wrapping_add(1);
+                }
+            }
+            offset += kcp::KCP_OVERHEAD + len;
+        }
     }
 }
 impl Write for UdpOutput {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        // 创建一个缓冲区，用于存储消息内容
-        let mut buffer = Vec::new();
+        // 复用缓冲区，用于存储消息内容
+        self.buffer.clear();
 
         // 写入通道头部
-        buffer.push(Kcp2KChannel::Reliable.into());
+        self.buffer.push(Kcp2KChannel::Reliable.into());
 
         // 写入握手 cookie 以防止 UDP 欺骗
-        buffer.extend_from_slice(&self.cookie.to_le_bytes());
+        self.buffer.extend_from_slice(&wire_cookie(&self.fault, *self.cookie, &self.stats).to_le_bytes());
 
         // 写入 data
-        buffer.extend_from_slice(buf);
+        self.buffer.extend_from_slice(buf);
+        self.inspect_outgoing(buf);
 
         // 发送数据
         match match *self.kcp2k_mode {
             // 客户端
-            Kcp2KMode::Client => self.socket.send(&buffer),
+            Kcp2KMode::Client => self.socket.send(&self.buffer),
             // 服务器
-            Kcp2KMode::Server => self.socket.send_to(&buffer, &self.client_sock_addr),
+            Kcp2KMode::Server => self.socket.send_to(&self.buffer, &self.client_sock_addr),
         } {
             // 发送成功
-            Ok(_) => Ok(buf.len()),
+            Ok(_) => {
+                self.stats.value_mut().record_sent(self.buffer.len());
+                Ok(buf.len())
+            }
             // 发送失败
-            Err(err) => Err(err),
+            Err(err) => {
+                SendErrorInfo::record(&self.last_send_error, &err);
+                Err(err)
+            }
         }
     }
 
@@ -70,46 +174,138 @@ impl Write for UdpOutput {
     }
 }
 
+// 连接与 UdpOutput 共享的故障注入设置，只在测试或启用 testing 特性时存在
+#[cfg(any(test, feature = "testing"))]
+type FaultInjection = Arc<FaultConfig>;
+#[cfg(not(any(test, feature = "testing")))]
+type FaultInjection = ();
+
+// 写入数据包的 cookie，启用故障注入时每个数据包使用不同的错误 cookie
+#[allow(unused)]
+fn wire_cookie(fault: &FaultInjection, cookie: u32, stats: &ConnectionStats) -> u32 {
+    #[cfg(any(test, feature = "testing"))]
+    if fault.corrupt_cookie {
+        return cookie.wrapping_add(stats.packets_sent as u32 + 1);
+    }
+    cookie
+}
+
+// ping 时间戳使用的毫秒时钟，按 2^32 回绕；只用于回绕减法，回绕前后的差值仍然正确
+fn ping_clock(elapsed: Duration) -> u32 {
+    elapsed.as_millis() as u32
+}
+
 impl Kcp2kConnection {
-    pub(crate) fn new(id: u64, config: Arc<Kcp2KConfig>, kcp2k_mode: Arc<Kcp2KMode>, socket: Arc<Socket>, client_sock_addr: Arc<SockAddr>, callback_func: CallbackFuncType) -> Self {
+    pub(crate) fn new(id: ConnId, config: Arc<Kcp2KConfig>, kcp2k_mode: Arc<Kcp2KMode>, socket: Arc<Socket>, client_sock_addr: Arc<SockAddr>, callback_func: Arc<CallbackFuncType>, data_callback_func: Arc<Option<SharedDataCallbackType>>) -> Self {
         // generate cookie
         let cookie = match *kcp2k_mode {
             Kcp2KMode::Client => Arc::new(0),
             Kcp2KMode::Server => Arc::new(generate_cookie()),
         };
 
+        // 连接统计信息，与 UdpOutput 共享
+        let stats: Arc<ConnectionStats> = Default::default();
+        // 最近一次发送错误，与 UdpOutput 共享
+        let last_send_error: Arc<Option<SendErrorInfo>> = Default::default();
+
         // set up kcp over a reliable channel (that's what kcp is for)
-        let udp_output = UdpOutput::new(kcp2k_mode.clone(), cookie.clone(), socket.clone(), client_sock_addr.clone());
+        // 注意：client_sock_addr 以共享单元的方式传入，对端地址只有这一个来源
+        // 快速重传恢复状态，由 UdpOutput 检测重传，由收到的 una 结束
+        let recovery_point: Arc<Option<u32>> = Default::default();
+        // 服务器的 socket 由所有连接共享，每个连接用自己的单元持有它，迁移时只替换本连接使用的 socket
+        let socket = Arc::new(socket);
+        let fault: FaultInjection = Default::default();
+
+        let udp_output = UdpOutput::new(kcp2k_mode.clone(), cookie.clone(), socket.clone(), client_sock_addr.clone(), stats.clone(), last_send_error.clone(), recovery_point.clone(), fault.clone());
 
         // kcp
         let mut kcp = Kcp::new(0, udp_output);
         // set nodelay.
         // note that kcp uses 'nocwnd' internally so we negate the parameter
         kcp.set_nodelay(if config.no_delay { true } else { false }, config.interval, config.fast_resend, !config.congestion_window);
-        kcp.set_wndsize(config.send_window_size, config.receive_window_size);
+        // 认证前使用较小的发送窗口，避免向扫描器与半开连接发送大量数据，认证后再恢复为配置的窗口大小
+        kcp.set_wndsize(config.send_window_size.min(Kcp2KConfig::PRE_AUTH_WINDOW_SIZE), config.receive_window_size);
 
         // IMPORTANT: high level needs to add 1 channel byte to each raw
         // message. so while Kcp.MTU_DEF is perfect, we actually need to
         // tell kcp to use MTU-1 so we can still put the header into the
         // message afterward.
-        let _ = kcp.set_mtu(config.mtu - Kcp2KConfig::METADATA_SIZE_RELIABLE);
+        let _ = kcp.set_mtu(config.reliable_mtu());
 
         // set maximum retransmits (aka dead_link)
         kcp.set_maximum_resend_times(config.max_retransmits);
 
+        // 对端是否已经确认认证
+        let peer_confirmed = *kcp2k_mode == Kcp2KMode::Client;
+
+        // 当前的最大重传次数，可在运行时修改
+        let max_retransmits = config.max_retransmits;
+
+        // 出站带宽令牌，初始为满桶
+        let out_tokens = Self::out_budget_capacity(&config);
+
+        // 入站消息令牌，初始为满桶
+        let in_tokens = config.max_messages_per_sec_per_conn.unwrap_or(0) as f64;
+
         let connection = Kcp2kConnection {
             id,
             config,
             kcp2k_mode,
             callback_func,
+            data_callback_func,
             cookie,
             state: Arc::new(Kcp2KConnectionStates::Connected),
             socket,
             client_sock_addr,
             kcp: Arc::new(kcp),
             watch: Instant::now(),
+            kcp_epoch: Default::default(),
             last_send_ping_time: Default::default(),
             last_recv_time: Default::default(),
+            send_buffer: Default::default(),
+            stats,
+            unreliable_message_id: Default::default(),
+            unreliable_seen: Default::default(),
+            unreliable_id_range: Default::default(),
+            unreliable_received_ids: Default::default(),
+            rx_srtt: Default::default(),
+            rx_rttval: Default::default(),
+            peer_rtt: Default::default(),
+            disconnect_deadline: Default::default(),
+            last_send_error,
+            connected_waker: Default::default(),
+            out_tokens: Arc::new(out_tokens),
+            out_tokens_time: Default::default(),
+            last_ping_rtt: Default::default(),
+            fault,
+            #[cfg(any(test, feature = "testing"))]
+            pending_authentication: Default::default(),
+            #[cfg(feature = "debug-capture")]
+            last_raw_input: Default::default(),
+            receipt_message_id: Default::default(),
+            send_retry_queue: Default::default(),
+            max_retransmits: Arc::new(max_retransmits),
+            assigned_id: Default::default(),
+            reliable_segments: Default::default(),
+            hello_sn: Default::default(),
+            // 客户端收到服务器的 Hello 时服务器已经完成认证；服务器需要等待客户端确认 Hello
+            peer_confirmed: Arc::new(peer_confirmed),
+            deferred_unreliable: Default::default(),
+            unacked_messages: Default::default(),
+            acked_bytes: Default::default(),
+            preauth_segments: Default::default(),
+            unreliable_batch: Default::default(),
+            recovery_point,
+            muted: Default::default(),
+            congested_since: Default::default(),
+            congested: Default::default(),
+            stats_log_mark: Default::default(),
+            unknown_headers: Default::default(),
+            in_tokens: Arc::new(in_tokens),
+            in_tokens_time: Default::default(),
+            rate_abuse: Default::default(),
+            rate_limited_messages: Default::default(),
+            user_data: Default::default(),
         };
 
         connection
@@ -117,22 +313,57 @@ impl Kcp2kConnection {
 
     // 发送 Hello 消息
     pub(crate) fn send_hello(&self) {
-        let _ = self.send_reliable(Kcp2KReliableHeader::Hello, Default::default());
+        // 服务器在 Hello 中携带分配的连接 ID，客户端发送空负载
+        let conn_id = self.id.0.to_le_bytes();
+        let payload: &[u8] = match *self.kcp2k_mode {
+            Kcp2KMode::Server => &conn_id,
+            Kcp2KMode::Client => &[],
+        };
+        let _ = self.send_reliable(Kcp2KReliableHeader::Hello, payload);
     }
 
     pub(crate) fn raw_input(&mut self, segment: &[u8]) -> Result<(), Kcp2KError> {
-        if segment.len() <= 5 {
-            let err = Kcp2KError::InvalidReceive(format!("{}: Received invalid message with length={}. Disconnecting the connection.", std::any::type_name::<Self>(), segment.len()));
-            self.on_error(err.clone());
-            return Err(err);
+        self.raw_input_at(segment, Instant::now())
+    }
+
+    // 使用调用方提供的接收时间处理数据报，用于更新最后接收时间与测量 RTT
+    pub(crate) fn raw_input_at(&mut self, segment: &[u8], now: Instant) -> Result<(), Kcp2KError> {
+        let elapsed_time = now.saturating_duration_since(self.watch);
+        // 记录接收统计
+        self.stats.value_mut().record_received(segment.len());
+
+        // 保存原始数据报用于调试，复用上一次的缓冲区
+        #[cfg(feature = "debug-capture")]
+        {
+            let last_raw_input = self.last_raw_input.value_mut().get_or_insert_with(Vec::new);
+            last_raw_input.clear();
+            last_raw_input.extend_from_slice(segment);
         }
 
+        // 解析通道与 cookie
+        let frame = match parse_frame(segment) {
+            Ok(frame) => frame,
+            Err(err) => {
+                let channel = segment.first().and_then(|channel| Kcp2KChannel::try_from(*channel).ok()).unwrap_or(Kcp2KChannel::None);
+                // 长度足够的不可靠数据报解析失败只可能是头部未定义，cookie 正确时按未知头部处理
+                if channel == Kcp2KChannel::Unreliable
+                    && segment.len() > Kcp2KConfig::METADATA_SIZE_UNRELIABLE
+                    && (*self.cookie == 0 || *self.cookie == u32::from_le_bytes([segment[1], segment[2], segment[3], segment[4]]))
+                {
+                    self.handle_unknown_header(err.clone(), channel);
+                } else {
+                    self.on_error(err.clone(), channel);
+                }
+                return Err(err);
+            }
+        };
+
         // cookie
-        let message_cookie = u32::from_le_bytes([segment[1], segment[2], segment[3], segment[4]]);
+        let message_cookie = frame.cookie;
 
         if *self.cookie == 0 {
             self.cookie.set_value(message_cookie);
-        } else if *self.state == Kcp2KConnectionStates::Authenticated && *self.cookie.value() != message_cookie {
+        } else if matches!(self.state.value(), Kcp2KConnectionStates::Authenticated | Kcp2KConnectionStates::Suspect) && *self.cookie.value() != message_cookie {
             // 如果连接已经通过验证，但是收到了带有不同 cookie 的消息，那么这可能是由于客户端的 Hello 消息被多次传输，或者攻击者尝试进行 UDP 欺骗。
             let err = Kcp2KError::InvalidReceive(format!(
                 "{}: Dropped message with invalid cookie: {:?} from {:?} expected: {:?} state: {:?}. This can happen if the client's Hello message was transmitted multiple times, or if an attacker attempted UDP spoofing.",
@@ -142,44 +373,88 @@ impl Kcp2kConnection {
                 self.cookie,
                 self.state
             ));
-            self.on_error(err.clone());
-            self.send_disconnect();
+            self.handle_receive_error(err.clone(), frame.channel);
             return Err(err);
         }
 
-        // 消息
-        let kcp_data = &segment[5..];
-
         // 更新最后接收时间
-        self.last_recv_time.set_value(self.watch.elapsed());
+        self.last_recv_time.set_value(elapsed_time);
+
+        // 断开宽限期内收到对端的数据包，恢复连接
+        if *self.state == Kcp2KConnectionStates::Suspect {
+            self.state.set_value(Kcp2KConnectionStates::Authenticated);
+            self.on_resumed();
+        }
 
         // 根据通道类型处理消息
-        match Kcp2KChannel::from(segment[0]) {
-            Kcp2KChannel::Reliable => self.raw_input_reliable(kcp_data),
-            Kcp2KChannel::Unreliable => self.raw_input_unreliable(kcp_data),
-            _ => {
-                let err = Kcp2KError::Unexpected(format!("{}: Received message with unexpected channel. Disconnecting the connection.", std::any::type_name::<Self>()));
-                self.on_error(err.clone());
-                Err(err)
-            }
+        match frame.header {
+            None => self.raw_input_reliable(frame.payload, elapsed_time),
+            Some(header) => self.raw_input_unreliable(header, frame.payload),
         }
     }
 
+    #[cfg(test)]
     pub(crate) fn tick_incoming(&self) {
+        self.tick_incoming_at(Instant::now());
+    }
+
+    // 使用调用方提供的当前时间处理接收，同一次 tick 中的所有连接共享同一个时间戳
+    pub(crate) fn tick_incoming_at(&self, now: Instant) {
+        // 可靠断开等待中，只等待对端确认，不再处理消息
+        if self.disconnect_deadline.is_some() {
+            return;
+        }
         // 获取经过的时间
-        let elapsed_time = self.watch.elapsed();
+        let elapsed_time = now.saturating_duration_since(self.watch);
         // 根据状态处理不同的逻辑
         match self.state.value() {
             Kcp2KConnectionStates::Connected => self.tick_incoming_connected(elapsed_time),
             Kcp2KConnectionStates::Authenticated => self.tick_incoming_authenticated(elapsed_time),
+            Kcp2KConnectionStates::Suspect => self.tick_incoming_suspect(elapsed_time),
             _ => {}
         }
     }
 
+    #[cfg(test)]
     pub(crate) fn tick_outgoing(&self) {
+        self.tick_outgoing_at(Instant::now());
+    }
+
+    // 使用调用方提供的当前时间更新 KCP
+    pub(crate) fn tick_outgoing_at(&self, now: Instant) {
+        let elapsed_time = now.saturating_duration_since(self.watch);
         match self.state.value() {
-            Kcp2KConnectionStates::Connected | Kcp2KConnectionStates::Authenticated => {
-                let _ = self.kcp.value_mut().update(self.watch.elapsed().as_millis() as u32);
+            Kcp2KConnectionStates::Connected | Kcp2KConnectionStates::Authenticated | Kcp2KConnectionStates::Suspect => {
+                let current = self.advance_kcp_clock(elapsed_time);
+                // 一直有未确认的分段，KCP 时钟没有机会重新计时而到达上限，按失效链接断开。KCP 不能再更新，可靠断开也无法等待确认
+                if current >= Kcp2KConfig::KCP_CLOCK_LIMIT {
+                    if self.disconnect_deadline.is_none() {
+                        self.on_error(Kcp2KError::Timeout("KCP clock reached its limit without an idle moment to restart.".to_string()), Kcp2KChannel::Reliable);
+                        self.on_disconnected(DisconnectReason::DeadLink);
+                    }
+                    self.state.set_value(Kcp2KConnectionStates::Disconnected);
+                    self.wake_connected();
+                    return;
+                }
+                self.retry_pending_sends();
+                self.flush_deferred_unreliable();
+                let _ = self.flush_unreliable_batch();
+                self.update_congestion(elapsed_time);
+                self.log_stats(elapsed_time);
+                // 出站带宽耗尽时本次不刷新，可靠数据留在 KCP 队列中等待
+                if self.out_budget().is_some_and(|tokens| tokens <= 0.0) {
+                    return;
+                }
+                let bytes_sent = self.stats.bytes_sent;
+                let _ = self.kcp.value_mut().update(current);
+                self.consume_out_budget((self.stats.bytes_sent - bytes_sent) as usize);
+                // 可靠断开等待中：所有数据都被确认、链路失效或超过等待时间后才真正断开
+                if let Some(deadline) = *self.disconnect_deadline.value()
+                    && (self.kcp.wait_snd() == 0 || self.kcp.is_dead_link() || elapsed_time >= deadline)
+                {
+                    self.state.set_value(Kcp2KConnectionStates::Disconnected);
+                    self.wake_connected();
+                }
             }
             _ => {}
         }
@@ -189,36 +464,467 @@ impl Kcp2kConnection {
 #[allow(unused)]
 impl Kcp2kConnection {
     pub fn send_data(&self, data: &[u8], channel: Kcp2KChannel) -> Result<(), Kcp2KError> {
+        // 认证完成前对端会把数据消息视为协议错误并断开连接，因此在本地直接拒绝
+        match self.state.value() {
+            Kcp2KConnectionStates::Authenticated | Kcp2KConnectionStates::Suspect => {}
+            Kcp2KConnectionStates::Disconnected => return Err(Kcp2KError::ConnectionClosed("connection closed".to_string())),
+            _ => return Err(Kcp2KError::ConnectionClosed("not authenticated yet".to_string())),
+        }
         // 如果数据为空，则返回错误
         if data.is_empty() {
             let err = Kcp2KError::InvalidSend("send_data: tried sending empty message. This should never happen. Disconnecting.".to_string());
-            self.on_error(err.clone());
+            self.on_error(err.clone(), channel);
             return Err(err);
         }
         // 根据通道类型发送数据
         match channel {
-            Kcp2KChannel::Reliable => self.send_reliable(Kcp2KReliableHeader::Data, data),
-            Kcp2KChannel::Unreliable => self.send_unreliable(Kcp2KUnreliableHeader::Data, data),
+            Kcp2KChannel::Reliable => {
+                self.send_reliable(Kcp2KReliableHeader::Data, data)?;
+                // 立即刷新，不等待下一个 interval
+                if self.config.flush_on_send {
+                    self.flush_now();
+                }
+                Ok(())
+            }
+            Kcp2KChannel::Unreliable => {
+                // 不可靠消息不会分片，超过一个数据报的消息会被系统拒绝或被分片丢弃
+                if data.len() > self.unreliable_max_size() {
+                    let err = Kcp2KError::InvalidSend(format!("send_data: unreliable message of {} bytes exceeds the maximum of {} bytes.", data.len(), self.unreliable_max_size()));
+                    self.on_error(err.clone(), Kcp2KChannel::Unreliable);
+                    return Err(err);
+                }
+                // 服务器在客户端确认 Hello 之前发送的不可靠数据会被客户端当作认证前的消息丢弃，因此先暂存
+                if !*self.peer_confirmed {
+                    return self.defer_unreliable(data);
+                }
+                if self.config.coalesce_unreliable {
+                    return self.coalesce_unreliable(data);
+                }
+                self.send_unreliable(Kcp2KUnreliableHeader::Data, data)
+            }
             _ => {
                 let err = Kcp2KError::InvalidSend("send_data: channel disconnected.".to_string());
-                self.on_error(err.clone());
+                self.on_error(err.clone(), Kcp2KChannel::None);
                 Err(err)
             }
         }
     }
 
+    // 发送带标签的可靠消息，对端通过 OnData 回调的 tag 分发到应用层的不同处理逻辑
+    // 标签只是消息上的一个字节，不同标签的消息仍然共用同一个有序的 KCP 传输，一条消息丢包重传时会阻塞其后所有标签的消息；标签 0 与 send_data 相同
+    pub fn send_tagged(&self, tag: u8, data: &[u8]) -> Result<(), Kcp2KError> {
+        if tag == 0 {
+            return self.send_data(data, Kcp2KChannel::Reliable);
+        }
+        match self.state.value() {
+            Kcp2KConnectionStates::Authenticated | Kcp2KConnectionStates::Suspect => {}
+            Kcp2KConnectionStates::Disconnected => return Err(Kcp2KError::ConnectionClosed("connection closed".to_string())),
+            _ => return Err(Kcp2KError::ConnectionClosed("not authenticated yet".to_string())),
+        }
+        if data.is_empty() {
+            let err = Kcp2KError::InvalidSend("send_tagged: tried sending empty message.".to_string());
+            self.on_error(err.clone(), Kcp2KChannel::Reliable);
+            return Err(err);
+        }
+        // 负载: [标签 u8][数据]
+        let mut payload = Vec::with_capacity(1 + data.len());
+        payload.push(tag);
+        payload.extend_from_slice(data);
+        self.send_reliable(Kcp2KReliableHeader::TaggedData, &payload)?;
+        if self.config.flush_on_send {
+            self.flush_now();
+        }
+        Ok(())
+    }
+
+    // 原子地发送一组可靠消息：先检查全部消息合法且所需分段数不超过发送窗口的剩余空间，再全部排队；
+    // 任何一条不满足时一条都不发送，窗口不足时返回 Congestion，避免对端只收到部分状态
+    pub fn send_reliable_batch(&self, messages: &[&[u8]]) -> Result<(), Kcp2KError> {
+        match self.state.value() {
+            Kcp2KConnectionStates::Authenticated | Kcp2KConnectionStates::Suspect => {}
+            Kcp2KConnectionStates::Disconnected => return Err(Kcp2KError::ConnectionClosed("connection closed".to_string())),
+            _ => return Err(Kcp2KError::ConnectionClosed("not authenticated yet".to_string())),
+        }
+        let mss = self.config.reliable_mtu() - kcp::KCP_OVERHEAD;
+        let mut segments = 0;
+        for message in messages {
+            if message.is_empty() || message.len() > self.reliable_max_size() {
+                return Err(Kcp2KError::InvalidSend(format!("send_reliable_batch: message of {} bytes must be between 1 and {} bytes.", message.len(), self.reliable_max_size())));
+            }
+            segments += (self.reliable_message_overhead() + message.len()).div_ceil(mss);
+        }
+        let available = (self.kcp.snd_wnd() as usize).saturating_sub(self.kcp.wait_snd());
+        if segments > available {
+            return Err(Kcp2KError::Congestion(format!("send_reliable_batch: {} messages need {} segments but only {} are available in the send window.", messages.len(), segments, available)));
+        }
+        for message in messages {
+            self.send_reliable(Kcp2KReliableHeader::Data, message)?;
+        }
+        if self.config.flush_on_send {
+            self.flush_now();
+        }
+        Ok(())
+    }
+
+    // 发送可以降级的“最新状态”消息（如位置同步），返回实际使用的通道。
+    // 启用 downgrade_under_congestion 且可靠队列持续积压时改走不可靠通道，避免在差的链路上继续堆积可靠数据；
+    // 降级后的消息可能丢失或乱序，应用层应只使用最新收到的状态
+    pub fn send_latest_state(&self, data: &[u8]) -> Result<Kcp2KChannel, Kcp2KError> {
+        let channel = match self.is_congested() && data.len() <= self.unreliable_max_size() {
+            true => Kcp2KChannel::Unreliable,
+            false => Kcp2KChannel::Reliable,
+        };
+        self.send_data(data, channel)?;
+        Ok(channel)
+    }
+
+    // 可靠队列是否处于持续积压状态，只在启用 downgrade_under_congestion 时判断
+    pub fn is_congested(&self) -> bool {
+        self.config.downgrade_under_congestion && *self.congested
+    }
+
+    // 按 log_stats_interval 输出吞吐统计，速率根据两次输出之间的累计字节数计算，不影响 take_stats
+    fn log_stats(&self, elapsed_time: Duration) {
+        let Some(interval) = self.config.log_stats_interval else {
+            return;
+        };
+        let (last_time, last_sent, last_received) = *self.stats_log_mark.value();
+        let period = elapsed_time.saturating_sub(last_time);
+        if period < interval {
+            return;
+        }
+        let (sent, received) = (self.stats.lifetime_bytes_sent, self.stats.lifetime_bytes_received);
+        let seconds = period.as_secs_f64();
+        let loss = match self.unreliable_loss_rate() {
+            Some(rate) => format!("{:.3}", rate),
+            None => "n/a".to_string(),
+        };
+        info!(
+            instance = self.config.instance_name.as_deref();
+            "{} stats conn_id={} remote={} bps_in={:.0} bps_out={:.0} rtt_ms={} loss={}",
+            self.config.log_prefix(),
+            self.id,
+            self.remote_address(),
+            (received - last_received) as f64 * 8.0 / seconds,
+            (sent - last_sent) as f64 * 8.0 / seconds,
+            self.rtt_ms(),
+            loss
+        );
+        self.stats_log_mark.set_value((elapsed_time, sent, received));
+    }
+
+    // 每次 tick 检查可靠队列，积压持续超过 DOWNGRADE_SUSTAIN 时视为拥塞，队列回落到阈值以下时清除
+    fn update_congestion(&self, elapsed_time: Duration) {
+        let threshold = self.config.send_window_size as usize * Kcp2KConfig::DOWNGRADE_QUEUE_FACTOR;
+        if self.kcp.wait_snd() > threshold {
+            let since = self.congested_since.unwrap_or(elapsed_time);
+            self.congested_since.set_value(Some(since));
+            self.congested.set_value(elapsed_time >= since + Duration::from_millis(Kcp2KConfig::DOWNGRADE_SUSTAIN));
+        } else {
+            self.congested_since.set_value(None);
+            self.congested.set_value(false);
+        }
+    }
+
+    // 立即刷新 KCP，返回本次刷新实际写入 socket 的字节数（包含通道头部与 cookie），便于自行统计带宽
+    pub fn flush_and_report(&self) -> usize {
+        self.flush_now()
+    }
+
+    // 发送可靠的质询消息，认证完成之前也可以发送，对端通过 OnChallenge 回调接收，可用于在基础握手之上实现质询-应答认证
+    pub fn send_challenge(&self, data: &[u8]) -> Result<(), Kcp2KError> {
+        if *self.state == Kcp2KConnectionStates::Disconnected || self.disconnect_deadline.is_some() {
+            return Err(Kcp2KError::ConnectionClosed("connection closed".to_string()));
+        }
+        self.send_reliable(Kcp2KReliableHeader::Challenge, data)?;
+        if self.config.flush_on_send {
+            self.flush_now();
+        }
+        Ok(())
+    }
+
+    // 发送带回执的可靠消息，返回消息 ID；对端交给应用层后回复回执，通过 OnReceipt 回调通知
+    pub fn send_reliable_with_receipt(&self, data: &[u8]) -> Result<u32, Kcp2KError> {
+        if !self.config.reliable_receipts {
+            return Err(Kcp2KError::InvalidSend("send_reliable_with_receipt: reliable_receipts is disabled.".to_string()));
+        }
+        if !matches!(self.state.value(), Kcp2KConnectionStates::Authenticated | Kcp2KConnectionStates::Suspect) {
+            return Err(Kcp2KError::ConnectionClosed("not authenticated yet".to_string()));
+        }
+        if data.is_empty() {
+            let err = Kcp2KError::InvalidSend("send_reliable_with_receipt: tried sending empty message.".to_string());
+            self.on_error(err.clone(), Kcp2KChannel::Reliable);
+            return Err(err);
+        }
+        let message_id = *self.receipt_message_id;
+        self.receipt_message_id.set_value(message_id.wrapping_add(1));
+
+        // 负载: [消息 ID u32][数据]
+        let mut payload = Vec::with_capacity(4 + data.len());
+        payload.extend_from_slice(&message_id.to_le_bytes());
+        payload.extend_from_slice(data);
+        self.send_reliable(Kcp2KReliableHeader::DataWithReceipt, &payload)?;
+        if self.config.flush_on_send {
+            self.flush_now();
+        }
+        Ok(message_id)
+    }
+
     // 发送断开连接通知
     pub fn send_disconnect(&self) {
+        // 可靠断开：已经在等待确认时不重复发送
+        if self.disconnect_deadline.is_some() {
+            return;
+        }
+        // 可靠断开：通过 KCP 重传直到对端确认，由 tick_outgoing 在确认后设置断开状态
+        if self.config.reliable_disconnect
+            && matches!(self.state.value(), Kcp2KConnectionStates::Connected | Kcp2KConnectionStates::Authenticated | Kcp2KConnectionStates::Suspect)
+            && self.send_reliable(Kcp2KReliableHeader::Disconnect, Default::default()).is_ok()
+        {
+            self.disconnect_deadline.set_value(Some(self.watch.elapsed() + Duration::from_millis(Kcp2KConfig::RELIABLE_DISCONNECT_WAIT)));
+            return;
+        }
+        // 先刷新 KCP，确保对端的可靠断开消息能够收到确认
+        let _ = self.kcp.value_mut().flush();
         // 发送多次断开连接通知以确保对方收到
         for _ in 0..5 {
             let _ = self.send_unreliable(Kcp2KUnreliableHeader::Disconnect, Default::default());
         }
         // 设置状态为断开
-        self.state.set_value(Kcp2KConnectionStates::Disconnected)
+        self.state.set_value(Kcp2KConnectionStates::Disconnected);
+        self.wake_connected();
+    }
+
+    // 主动断开连接：通知对端并触发原因为 Kicked 的 OnDisconnected，已经断开时不执行任何操作
+    pub fn disconnect(&self) {
+        self.on_disconnected(DisconnectReason::Kicked);
+    }
+
+    // 丢弃 KCP 接收队列中所有已收到但尚未处理的可靠消息，返回丢弃的消息数量。
+    // 注意：这会有意丢失数据（包括 ping 等控制消息），仅在切换状态需要丢弃过期命令时使用
+    pub fn discard_pending_recv(&self) -> usize {
+        let mut count = 0;
+        let mut buffer = Vec::new();
+        while let Ok(size) = self.kcp.peeksize() {
+            buffer.resize(size, 0);
+            if self.kcp.value_mut().recv(&mut buffer).is_err() {
+                break;
+            }
+            count += 1;
+        }
+        count
+    }
+
+    // 最近收到的原始数据报（包含通道与 cookie），用于调试
+    #[cfg(feature = "debug-capture")]
+    pub fn last_raw_input(&self) -> Option<Vec<u8>> {
+        self.last_raw_input.value().clone()
+    }
+
+    // 强制设置 cookie，仅用于测试与迁移工具
+    #[cfg(any(test, feature = "testing"))]
+    pub fn set_cookie(&self, cookie: u32) {
+        self.cookie.set_value(cookie);
+    }
+
+    // 设置故障注入，立即对之后发出的数据包生效，仅用于测试客户端的错误处理
+    #[cfg(any(test, feature = "testing"))]
+    pub(crate) fn set_fault_injection(&self, fault: FaultConfig) {
+        self.fault.set_value(fault);
+    }
+
+    // 获取连接的 cookie，服务器据此在客户端地址变化后找到原来的连接
+    pub(crate) fn cookie(&self) -> u32 {
+        *self.cookie
+    }
+
+    // 更新对端地址与发送使用的 socket，UdpOutput 与 raw_send 共享同一组单元，因此后续的可靠与不可靠消息都会从 socket 发往新地址
+    pub(crate) fn set_remote_addr(&self, socket: Arc<Socket>, addr: SockAddr) {
+        self.socket.set_value(socket);
+        self.client_sock_addr.set_value(addr);
+    }
+
+    // 向候选的新地址发送携带随机数的 ping，返回该随机数；对端的 pong 回显随机数，证明新地址可达
+    pub(crate) fn send_path_challenge(&self, socket: &Socket, addr: &SockAddr) -> u32 {
+        let nonce = generate_cookie();
+        // 负载与普通 ping 相同: [本端 RTT 估计值 u32][随机数 u32]
+        let mut payload = [0u8; 8];
+        payload[..4].copy_from_slice(&self.rtt_ms().to_le_bytes());
+        payload[4..].copy_from_slice(&nonce.to_le_bytes());
+        let datagram = frame_unreliable(*self.cookie, Kcp2KUnreliableHeader::Ping, &payload);
+        if let Err(e) = socket.send_to(&datagram, addr) {
+            warn!(instance = self.config.instance_name.as_deref(); "{} Failed to send path challenge to {:?}: {}", self.config.log_prefix(), addr.as_socket(), e);
+        }
+        nonce
+    }
+
+    // 获取最近一次发送失败时系统报告的错误类型，未发生过发送错误时返回 None
+    pub fn last_send_error(&self) -> Option<io::ErrorKind> {
+        self.last_send_error.map(|info| info.kind)
+    }
+
+    // 最近一次发送失败是否因为数据报过大（EMSGSIZE），可据此降低 MTU
+    pub fn last_send_error_is_message_too_large(&self) -> bool {
+        self.last_send_error.is_some_and(|info| info.message_too_large)
+    }
+
+    // 获取连接信息快照
+    pub fn info(&self) -> ConnectionInfo {
+        ConnectionInfo {
+            conn_id: self.id,
+            remote_address: self.remote_address(),
+            local_address: self.local_address(),
+            state: *self.state,
+            cookie: *self.cookie,
+            reliable_mtu: self.config.reliable_mtu(),
+            unreliable_mtu: self.config.unreliable_mtu(),
+            uptime: self.watch.elapsed(),
+            stats: self.stats(),
+        }
+    }
+
+    // 客户端本地地址变化：更新本地地址并重新发送 Hello，cookie 保持不变，服务器按 cookie 把连接迁移到新地址
+    pub(crate) fn on_local_address_changed(&self, local_addr: SockAddr) {
+        self.client_sock_addr.set_value(local_addr);
+        self.send_hello();
+    }
+
+    // 获取连接统计信息
+    pub fn stats(&self) -> ConnectionStats {
+        *self.stats.value()
+    }
+
+    // 获取统计信息快照并清零可重置的计数（字节数、包数等），用于按周期统计速率；累计总量不受影响
+    pub fn take_stats(&self) -> ConnectionStats {
+        self.stats.value_mut().take()
+    }
+
+    // 连接建立以来发送的总字节数（包含头部），不会被 take_stats 重置
+    pub fn lifetime_bytes_sent(&self) -> u64 {
+        self.stats.lifetime_bytes_sent
+    }
+
+    // 连接建立以来接收的总字节数（包含头部），不会被 take_stats 重置
+    pub fn lifetime_bytes_received(&self) -> u64 {
+        self.stats.lifetime_bytes_received
+    }
+
+    // 获取本端根据 KCP 确认测量的平滑 RTT（毫秒），尚未测量时返回 0
+    pub fn rtt_ms(&self) -> u32 {
+        *self.rx_srtt
+    }
+
+    // 与 rtt_ms 相同的平滑 RTT，尚未收到任何确认时返回 None，便于显示延迟或调整插值缓冲。
+    // kcp crate 不公开内部的 RTT，这里是本端根据 ACK 回显的时间戳按与 KCP 相同的算法独立计算的值
+    pub fn rtt(&self) -> Option<Duration> {
+        match *self.rx_srtt {
+            0 => None,
+            srtt => Some(Duration::from_millis(srtt as u64)),
+        }
+    }
+
+    // rtt() 的平均偏差，与 rtt() 一样由本端根据 ACK 计算，尚未收到任何确认时返回 None
+    pub fn rtt_variance(&self) -> Option<Duration> {
+        self.rtt()?;
+        Some(Duration::from_millis(*self.rx_rttval as u64))
+    }
+
+    // 立即发送一个 ping，不等待保活间隔，对端回复 pong 后可以通过 last_ping_rtt() 获取本次往返时间
+    pub fn ping(&self) -> Result<(), Kcp2KError> {
+        self.send_ping_message()
+    }
+
+    // 获取最近一次 ping/pong 测量的往返时间（保活 ping 也会更新），尚未收到 pong 时返回 None
+    pub fn last_ping_rtt(&self) -> Option<Duration> {
+        *self.last_ping_rtt
+    }
+
+    // 估算当前可靠队列全部被确认所需的时间：每个 RTT 最多发送一个发送窗口的分段，外加每轮等待一次 KCP 刷新间隔。
+    // 未考虑丢包重传与对端接收窗口，仅用于进度提示
+    pub fn estimated_drain_time(&self) -> Duration {
+        let segments = self.kcp.wait_snd() as u64;
+        if segments == 0 {
+            return Duration::ZERO;
+        }
+        let window = (self.kcp.snd_wnd() as u64).max(1);
+        let rounds = segments.div_ceil(window);
+        let round_trip = self.rtt_ms() as u64 + self.config.interval as u64;
+        Duration::from_millis(rounds * round_trip)
+    }
+
+    // 获取对端通过 ping 告知的 RTT（毫秒），对端未携带时返回 None
+    pub fn peer_rtt_ms(&self) -> Option<u32> {
+        *self.peer_rtt
+    }
+
+    // 运行时修改最大重传次数（dead_link）。
+    // 注意：如果新值低于某个分段当前的重传次数，下一次 tick 会立即判定为失效链接并断开连接
+    pub fn set_max_retransmits(&self, max_retransmits: u32) {
+        self.kcp.value_mut().set_maximum_resend_times(max_retransmits);
+        self.max_retransmits.set_value(max_retransmits);
+    }
+
+    // 获取当前的最大重传次数
+    pub fn max_retransmits(&self) -> u32 {
+        *self.max_retransmits
+    }
+
+    // 获取服务器在握手时分配的连接 ID（仅客户端），服务器未携带时返回 None
+    pub fn assigned_id(&self) -> Option<ConnId> {
+        *self.assigned_id
+    }
+
+    // 不可靠通道的丢包率（0.0 ~ 1.0），根据收到的消息 ID 中的空缺推算，只统计应用层数据消息
+    // 需要启用 unreliable_dedup（消息携带 ID），未启用或尚未收到消息时返回 None
+    pub fn unreliable_loss_rate(&self) -> Option<f64> {
+        let (first, highest) = (*self.unreliable_id_range.value())?;
+        let expected = highest.wrapping_sub(first) as u64 + 1;
+        let received = (*self.unreliable_received_ids).min(expected);
+        Some((expected - received) as f64 / expected as f64)
+    }
+
+    // 静音连接：握手、ping 与超时照常处理，但丢弃对端发送的应用数据，不再触发 OnData
+    pub fn set_muted(&self, muted: bool) {
+        self.muted.set_value(muted);
+    }
+
+    pub fn is_muted(&self) -> bool {
+        *self.muted
+    }
+
+    // 是否处于重传恢复中：KCP 重传了分段（快速重传或超时重传），且对端尚未确认重传前已发送的全部分段
+    pub fn in_recovery(&self) -> bool {
+        self.recovery_point.is_some()
+    }
+
+    // KCP 发送缓冲区与发送队列中的分段总数（已发送未确认 + 尚未发送），单位为分段。
+    // 持续增长说明发送速度超过了网络或对端的处理能力，可以在发送前检查并自行限流
+    pub fn wait_snd(&self) -> usize {
+        self.kcp.wait_snd()
+    }
+
+    // 获取对端已经确认的可靠数据字节数（累计，不含头部），可与已发送的数据量一起用于显示传输进度
+    pub fn acked_bytes(&self) -> u64 {
+        *self.acked_bytes
+    }
+
+    // 获取 KCP 实例使用的 conv，与外部 KCP 对端互通时用于排查 conv 不一致的问题
+    pub fn conv(&self) -> u32 {
+        self.kcp.conv()
+    }
+
+    // 获取连接状态
+    pub fn state(&self) -> Kcp2KConnectionStates {
+        *self.state
+    }
+
+    // 是否已完成 Hello 握手且尚未断开；断开宽限期（Suspect）内仍视为已认证
+    pub fn is_authenticated(&self) -> bool {
+        matches!(*self.state, Kcp2KConnectionStates::Authenticated | Kcp2KConnectionStates::Suspect)
     }
 
     // 获取连接 ID
-    pub fn connection_id(&self) -> u64 {
+    pub fn connection_id(&self) -> ConnId {
         self.id
     }
 
@@ -241,18 +947,17 @@ impl Kcp2kConnection {
 
     // 获取远程地址
     pub fn remote_address(&self) -> String {
+        match self.remote_socket_addr() {
+            None => "".to_string(),
+            Some(socket) => socket.to_string(),
+        }
+    }
+
+    // 获取远程地址，无法转换为 SocketAddr 时返回 None
+    pub fn remote_socket_addr(&self) -> Option<SocketAddr> {
         match self.kcp2k_mode.value() {
-            Kcp2KMode::Client => match self.socket.peer_addr() {
-                Ok(addr) => match addr.as_socket() {
-                    None => "".to_string(),
-                    Some(socket) => socket.to_string(),
-                },
-                Err(_) => "".to_string(),
-            },
-            Kcp2KMode::Server => match self.client_sock_addr.as_socket() {
-                None => "".to_string(),
-                Some(socket) => socket.to_string(),
-            },
+            Kcp2KMode::Client => self.socket.peer_addr().ok()?.as_socket(),
+            Kcp2KMode::Server => self.client_sock_addr.as_socket(),
         }
     }
 
@@ -262,134 +967,550 @@ impl Kcp2kConnection {
     pub fn reliable_max_message_size(mtu: u32, rcv_wnd: u32) -> usize {
         Self::reliable_max_message_size_unconstrained(mtu, rcv_wnd.min(255))
     }
+    // 不可靠消息不经过 KCP，只需扣除通道头部、cookie 和 1 字节的不可靠头部
     pub fn unreliable_max_message_size(mtu: u32) -> usize {
-        (mtu - kcp::KCP_OVERHEAD as u32 - 1) as usize
+        (mtu - Kcp2KConfig::METADATA_SIZE_UNRELIABLE as u32 - 1) as usize
+    }
+
+    // 当前连接可靠通道单条消息的最大长度
+    pub fn reliable_max_size(&self) -> usize {
+        let max_size = Self::reliable_max_message_size(self.config.mtu as u32, self.kcp.rcv_wnd() as u32);
+        match self.config.strict_cookie_on_reliable {
+            true => max_size - Kcp2KConfig::COOKIE_HEADER_SIZE,
+            false => max_size,
+        }
+    }
+
+    // 当前连接不可靠通道单条消息的最大长度，启用去重时需要扣除消息 ID
+    pub fn unreliable_max_size(&self) -> usize {
+        let max_size = Self::unreliable_max_message_size(self.config.mtu as u32);
+        match self.config.unreliable_dedup {
+            true => max_size - Kcp2KConfig::UNRELIABLE_MESSAGE_ID_SIZE,
+            false => max_size,
+        }
     }
 }
 
 impl Kcp2kConnection {
     fn on_authenticated(&self) {
-        if *self.kcp2k_mode == Kcp2KMode::Server {
+        #[cfg(any(test, feature = "testing"))]
+        let drop_hello_response = self.fault.drop_hello_response;
+        #[cfg(not(any(test, feature = "testing")))]
+        let drop_hello_response = false;
+        if *self.kcp2k_mode == Kcp2KMode::Server && !drop_hello_response {
+            // 记录 Hello 的分段序号，对端确认该序号后才发送暂存的不可靠数据
+            self.hello_sn.set_value(Some(*self.reliable_segments));
             self.send_hello();
         }
+        self.kcp.value_mut().set_wndsize(self.config.send_window_size, self.config.receive_window_size);
         self.state.set_value(Kcp2KConnectionStates::Authenticated);
+        self.wake_connected();
         self.on_connected();
     }
 
     fn on_connected(&self) {
-        (self.callback_func)(
+        (self.callback_func.value())(
             self,
             Callback {
                 r#type: CallbackType::OnConnected,
                 conn_id: self.id,
+                remote_addr: self.remote_socket_addr(),
+                // 服务器从收到第一个数据包开始计时，客户端从 connect() 开始计时
+                handshake_duration: self.watch.elapsed(),
                 ..Default::default()
             },
         );
     }
 
     fn on_data(&self, data: &[u8], kcp2k_channel: Kcp2KChannel) {
-        (self.callback_func)(
+        self.on_tagged_data(data, kcp2k_channel, 0);
+    }
+
+    fn on_tagged_data(&self, data: &[u8], kcp2k_channel: Kcp2KChannel, tag: u8) {
+        if *self.muted || !self.allow_incoming_message() {
+            return;
+        }
+        // 设置了借用形式的数据回调时直接传递切片，不再拷贝数据
+        if let Some(data_callback_func) = self.data_callback_func.value().clone() {
+            data_callback_func(self, self.id, data, kcp2k_channel, tag);
+            return;
+        }
+        (self.callback_func.value())(
             self,
             Callback {
                 r#type: CallbackType::OnData,
                 data: data.to_vec(),
                 channel: kcp2k_channel,
                 conn_id: self.id,
+                remote_addr: self.remote_socket_addr(),
+                tag,
                 ..Default::default()
             },
         );
     }
 
-    fn on_error(&self, error: Kcp2KError) {
-        (self.callback_func)(
+    // channel 为错误来源的通道，无法确定时为 None
+    fn on_error(&self, error: Kcp2KError, channel: Kcp2KChannel) {
+        (self.callback_func.value())(
             self,
             Callback {
                 r#type: CallbackType::OnError,
                 conn_id: self.id,
+                remote_addr: self.remote_socket_addr(),
+                channel,
                 error,
                 ..Default::default()
             },
         );
     }
 
-    fn on_disconnected(&self) {
-        // 如果连接已经断开，则不执行任何操作
-        if *self.state == Kcp2KConnectionStates::Disconnected {
+    // 报告对端数据引起的错误，并按照 Kcp2KConfig::should_disconnect 的策略决定是否断开连接
+    fn handle_receive_error(&self, error: Kcp2KError, channel: Kcp2KChannel) {
+        let fatal = self.config.should_disconnect(&error);
+        self.on_error(error, channel);
+        if fatal {
+            self.on_disconnected(DisconnectReason::ProtocolError);
+        }
+    }
+
+    // 处理 cookie 正确但头部未定义的消息：容忍模式下计数后忽略，超过预算才断开
+    fn handle_unknown_header(&self, error: Kcp2KError, channel: Kcp2KChannel) {
+        match self.config.unknown_header_budget {
+            Some(budget) => {
+                self.unknown_headers.set_value(self.unknown_headers.saturating_add(1));
+                self.on_error(error, channel);
+                if *self.unknown_headers > budget {
+                    self.on_error(Kcp2KError::InvalidReceive(format!("Received {} messages with unknown headers, the budget is {}.", *self.unknown_headers, budget)), channel);
+                    self.on_disconnected(DisconnectReason::ProtocolError);
+                }
+            }
+            None if channel == Kcp2KChannel::Reliable => self.handle_receive_error(error, channel),
+            None => self.on_error(error, channel),
+        }
+    }
+
+    // 收到的未知头部消息数，只在启用 unknown_header_budget 时计数
+    pub fn unknown_header_count(&self) -> u32 {
+        *self.unknown_headers
+    }
+
+    pub(crate) fn on_disconnected(&self, reason: DisconnectReason) {
+        // 如果连接已经断开或正在可靠断开，则不执行任何操作
+        if *self.state == Kcp2KConnectionStates::Disconnected || self.disconnect_deadline.is_some() {
             return;
         }
         // 发送断开连接通知
         self.send_disconnect();
         // 回调
-        (self.callback_func)(
+        (self.callback_func.value())(
             self,
             Callback {
                 r#type: CallbackType::OnDisconnected,
                 conn_id: self.id,
+                remote_addr: self.remote_socket_addr(),
+                disconnect_reason: reason,
                 ..Default::default()
             },
         );
     }
 
-    // 发送 ping
-    fn send_ping(&self) {
-        match self.config.is_reliable_ping {
-            true => {
-                let _ = self.send_reliable(Kcp2KReliableHeader::Ping, Default::default());
-            }
-            false => {
-                let _ = self.send_unreliable(Kcp2KUnreliableHeader::Ping, Default::default());
-            }
-        }
+    fn on_suspect(&self) {
+        (self.callback_func.value())(
+            self,
+            Callback {
+                r#type: CallbackType::OnSuspect,
+                conn_id: self.id,
+                remote_addr: self.remote_socket_addr(),
+                ..Default::default()
+            },
+        );
     }
 
-    fn send_reliable(&self, kcp2k_header_reliable: Kcp2KReliableHeader, data: &[u8]) -> Result<(), Kcp2KError> {
-        // 创建一个缓冲区，用于存储消息内容
-        let mut buffer = vec![];
-
-        // 写入通道头部
-        buffer.push(kcp2k_header_reliable.into());
+    fn on_resumed(&self) {
+        (self.callback_func.value())(
+            self,
+            Callback {
+                r#type: CallbackType::OnResumed,
+                conn_id: self.id,
+                remote_addr: self.remote_socket_addr(),
+                ..Default::default()
+            },
+        );
+    }
 
-        // 写入数据
-        if !data.is_empty() {
-            buffer.extend_from_slice(&data);
-        }
+    fn on_challenge(&self, data: &[u8]) {
+        (self.callback_func.value())(
+            self,
+            Callback {
+                r#type: CallbackType::OnChallenge,
+                conn_id: self.id,
+                remote_addr: self.remote_socket_addr(),
+                channel: Kcp2KChannel::Reliable,
+                data: data.to_vec(),
+                ..Default::default()
+            },
+        );
+    }
+
+    // 服务器在接受连接时拒绝，服务器没有为该客户端创建会话，因此不再发送断开通知
+    fn on_rejected(&self, reason_code: u8, message: &[u8]) {
+        self.state.set_value(Kcp2KConnectionStates::Disconnected);
+        self.wake_connected();
+        (self.callback_func.value())(
+            self,
+            Callback {
+                r#type: CallbackType::OnDisconnected,
+                conn_id: self.id,
+                remote_addr: self.remote_socket_addr(),
+                disconnect_reason: DisconnectReason::Rejected(reason_code),
+                data: message.to_vec(),
+                ..Default::default()
+            },
+        );
+    }
+
+    fn on_receipt(&self, receipt_id: u32) {
+        (self.callback_func.value())(
+            self,
+            Callback {
+                r#type: CallbackType::OnReceipt,
+                conn_id: self.id,
+                remote_addr: self.remote_socket_addr(),
+                receipt_id,
+                ..Default::default()
+            },
+        );
+    }
+
+    // 连接即将从服务器的连接表中移除，这是该连接的最后一个事件
+    pub(crate) fn on_removed(&self) {
+        (self.callback_func.value())(
+            self,
+            Callback {
+                r#type: CallbackType::OnRemoved,
+                conn_id: self.id,
+                remote_addr: self.remote_socket_addr(),
+                ..Default::default()
+            },
+        );
+    }
+
+    // 注册等待连接建立的 waker，认证完成或断开时唤醒
+    pub(crate) fn register_connected_waker(&self, waker: &Waker) {
+        self.connected_waker.set_value(Some(waker.clone()));
+    }
+
+    // 唤醒等待连接建立的任务
+    pub(crate) fn wake_connected(&self) {
+        if let Some(waker) = self.connected_waker.value_mut().take() {
+            waker.wake();
+        }
+    }
+
+    // 发送 ping，携带发送时间戳，对端回复 pong 后用于测量往返时间。
+    // 不支持 pong 的旧版本对端只解析前 4 字节，不会回复，此时 last_ping_rtt 保持不变
+    fn send_ping_message(&self) -> Result<(), Kcp2KError> {
+        // 负载: [本端 RTT 估计值 u32，0 表示未知][发送时间戳 u32]
+        let mut payload = [0u8; 8];
+        payload[..4].copy_from_slice(&self.rtt_ms().to_le_bytes());
+        payload[4..].copy_from_slice(&ping_clock(self.watch.elapsed()).to_le_bytes());
+        match self.config.is_reliable_ping {
+            true => self.send_reliable(Kcp2KReliableHeader::Ping, &payload),
+            false => self.send_unreliable(Kcp2KUnreliableHeader::Ping, &payload),
+        }
+    }
+
+    // 发送保活 ping，同时测量往返时间
+    fn send_ping(&self) {
+        let _ = self.send_ping_message();
+    }
+
+    // 处理收到的 ping，解析对端携带的 RTT 估计值；携带时间戳时通过同一通道回复 pong，旧版本对端的 ping 不携带时间戳
+    fn on_ping(&self, data: &[u8], channel: Kcp2KChannel) {
+        if data.len() >= 4 {
+            let rtt = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+            if rtt > 0 {
+                self.peer_rtt.set_value(Some(rtt));
+            }
+        }
+        if data.len() >= 8 {
+            let timestamp = &data[4..8];
+            let _ = match channel {
+                Kcp2KChannel::Reliable => self.send_reliable(Kcp2KReliableHeader::Pong, timestamp),
+                _ => self.send_unreliable(Kcp2KUnreliableHeader::Pong, timestamp),
+            };
+        }
+    }
+
+    // 处理收到的 pong，根据回显的时间戳计算往返时间
+    fn on_pong(&self, data: &[u8]) {
+        if data.len() >= 4 {
+            let timestamp = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+            let current = ping_clock(self.watch.elapsed());
+            self.last_ping_rtt.set_value(Some(Duration::from_millis(current.wrapping_sub(timestamp) as u64)));
+        }
+    }
+
+    // 解析 KCP 分段头部，根据 ACK 回显的时间戳更新 RTT
+    fn inspect_segments(&self, data: &[u8], elapsed_time: Duration) {
+        let current = self.kcp_clock(elapsed_time);
+        let mut offset = 0;
+        // 分段头部: conv(4) cmd(1) frg(1) wnd(2) ts(4) sn(4) una(4) len(4)
+        while offset + kcp::KCP_OVERHEAD <= data.len() {
+            let segment = &data[offset..];
+            let cmd = segment[4];
+            let ts = u32::from_le_bytes([segment[8], segment[9], segment[10], segment[11]]);
+            let una = u32::from_le_bytes([segment[16], segment[17], segment[18], segment[19]]);
+            let len = u32::from_le_bytes([segment[20], segment[21], segment[22], segment[23]]) as usize;
+            // 对端已经确认 Hello，说明对端已经完成认证
+            if !*self.peer_confirmed
+                && let Some(hello_sn) = *self.hello_sn.value()
+                && una.wrapping_sub(hello_sn) as i32 > 0
+            {
+                self.peer_confirmed.set_value(true);
+            }
+            self.update_acked_bytes(una);
+            // 对端确认了进入恢复时已发送的全部分段，退出恢复
+            if let Some(recovery_point) = *self.recovery_point.value()
+                && una.wrapping_sub(recovery_point) as i32 >= 0
+            {
+                self.recovery_point.set_value(None);
+            }
+            if cmd == KCP_CMD_PUSH && *self.state == Kcp2KConnectionStates::Connected {
+                self.preauth_segments.set_value(*self.preauth_segments + 1);
+            }
+            if cmd == KCP_CMD_ACK {
+                let rtt = current.wrapping_sub(ts) as i32;
+                if rtt >= 0 {
+                    self.update_rtt(rtt as u32);
+                }
+            }
+            offset += kcp::KCP_OVERHEAD + len;
+        }
+    }
+
+    // 根据对端的 una（对端期望接收的下一个分段序号）累计已确认的字节数
+    fn update_acked_bytes(&self, una: u32) {
+        let unacked_messages = self.unacked_messages.value_mut();
+        while let Some(&(end_sn, bytes)) = unacked_messages.front() {
+            if una.wrapping_sub(end_sn) as i32 >= 0 {
+                self.acked_bytes.set_value(*self.acked_bytes + bytes as u64);
+                unacked_messages.pop_front();
+            } else {
+                break;
+            }
+        }
+    }
+
+    // 更新平滑 RTT，与 KCP 内部的计算方式一致
+    fn update_rtt(&self, rtt: u32) {
+        if *self.rx_srtt == 0 {
+            self.rx_srtt.set_value(rtt.max(1));
+            self.rx_rttval.set_value(rtt / 2);
+        } else {
+            let srtt = *self.rx_srtt;
+            let delta = rtt.abs_diff(srtt);
+            self.rx_rttval.set_value((3 * *self.rx_rttval + delta) / 4);
+            self.rx_srtt.set_value(((7 * srtt + rtt) / 8).max(1));
+        }
+    }
+
+    // 每条可靠消息在数据之外的长度：头部，严格模式下还有 cookie
+    fn reliable_message_overhead(&self) -> usize {
+        match self.config.strict_cookie_on_reliable {
+            true => 1 + Kcp2KConfig::COOKIE_HEADER_SIZE,
+            false => 1,
+        }
+    }
+
+    fn send_reliable(&self, kcp2k_header_reliable: Kcp2KReliableHeader, data: &[u8]) -> Result<(), Kcp2KError> {
+        // 复用连接的发送缓冲区，避免每次发送都分配内存（连接只在 tick 所在线程中使用）
+        let buffer = self.send_buffer.value_mut();
+        buffer.clear();
+
+        // 写入通道头部
+        buffer.push(kcp2k_header_reliable.into());
+
+        // 严格模式下在消息内也写入 cookie
+        if self.config.strict_cookie_on_reliable {
+            buffer.extend_from_slice(&wire_cookie(&self.fault, *self.cookie, &self.stats).to_le_bytes());
+        }
+
+        // 写入数据
+        if !data.is_empty() {
+            buffer.extend_from_slice(&data);
+        }
 
         // 通过 KCP 发送处理
-        match self.kcp.value_mut().send(&buffer) {
-            Ok(_) => Ok(()),
+        match self.kcp.value_mut().send(buffer.as_slice()) {
+            Ok(_) => {
+                // 记录已经排队的分段数（非流模式下每条消息至少占用一个分段），用于推算分段序号
+                let mss = self.config.reliable_mtu() - kcp::KCP_OVERHEAD;
+                let segments = buffer.len().div_ceil(mss).max(1) as u32;
+                self.reliable_segments.set_value(self.reliable_segments.wrapping_add(segments));
+                // 记录消息最后一个分段之后的序号与数据长度，对端的 una 超过该序号时计入已确认字节数
+                self.unacked_messages.value_mut().push_back((*self.reliable_segments, data.len()));
+                Ok(())
+            }
             Err(e) => {
-                let err = Kcp2KError::InvalidSend(format!("{}: 发送失败，错误码={}，内容长度={}", "send_reliable", e, data.len()));
-                self.on_error(err.clone());
+                let err = Kcp2KError::InvalidSend(self.describe_kcp_send_error(&e, data.len()));
+                self.on_error(err.clone(), Kcp2KChannel::Reliable);
                 Err(err)
             }
         }
     }
 
+    // 将 KCP 的发送错误转换为可读的说明
+    fn describe_kcp_send_error(&self, error: &kcp::Error, len: usize) -> String {
+        match error {
+            kcp::Error::UserBufTooBig => format!("send_reliable: message of {} bytes exceeds the max fragment count, the maximum is {} bytes. Increase receive_window_size or mtu, or reduce the message size.", len, self.reliable_max_size()),
+            kcp::Error::IoError(e) => format!("send_reliable: I/O error while queueing a message of {} bytes: {}", len, e),
+            _ => format!("send_reliable: KCP rejected a message of {} bytes: {}", len, error),
+        }
+    }
+
     fn raw_send(&self, data: &[u8]) -> Result<(), Kcp2KError> {
+        // 已有等待重试的数据时直接排队，避免新数据越过旧数据
+        if !self.send_retry_queue.is_empty() {
+            return self.queue_send_retry(data);
+        }
+        match self.raw_send_once(data) {
+            Ok(_) => Ok(()),
+            // 发送缓冲区已满，放入重试队列，下一次 tick 再发送
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                self.stats.value_mut().send_would_block += 1;
+                self.queue_send_retry(data)
+            }
+            Err(e) => {
+                SendErrorInfo::record(&self.last_send_error, &e);
+                match is_message_too_large(&e) {
+                    true => Err(Kcp2KError::MessageTooLarge(format!("{}: datagram of {} bytes rejected by the OS, mtu may be too high", e, data.len()))),
+                    false => Err(Kcp2KError::io("raw_send", &e)),
+                }
+            }
+        }
+    }
+
+    fn raw_send_once(&self, data: &[u8]) -> io::Result<()> {
         match self.kcp2k_mode.value() {
-            Kcp2KMode::Client => match self.socket.send(&data) {
-                Ok(_) => Ok(()),
-                Err(e) => Err(Kcp2KError::SendError(e.to_string())),
-            },
-            Kcp2KMode::Server => match self.socket.send_to(&data, &self.client_sock_addr) {
-                Ok(_) => Ok(()),
-                Err(e) => Err(Kcp2KError::SendError(e.to_string())),
-            },
+            Kcp2KMode::Client => self.socket.send(data)?,
+            Kcp2KMode::Server => self.socket.send_to(data, &self.client_sock_addr)?,
+        };
+        self.stats.value_mut().record_sent(data.len());
+        self.consume_out_budget(data.len());
+        Ok(())
+    }
+
+    // 放入重试队列，队列已满时丢弃
+    fn queue_send_retry(&self, data: &[u8]) -> Result<(), Kcp2KError> {
+        if self.send_retry_queue.len() >= Kcp2KConfig::SEND_RETRY_QUEUE_LIMIT {
+            return Err(Kcp2KError::Congestion(format!("raw_send: dropped {} bytes, socket send buffer is full and the retry queue is full", data.len())));
+        }
+        self.send_retry_queue.value_mut().push_back(data.to_vec());
+        Ok(())
+    }
+
+    // 暂存对端确认认证之前的不可靠数据，超过上限时丢弃
+    fn defer_unreliable(&self, data: &[u8]) -> Result<(), Kcp2KError> {
+        if self.deferred_unreliable.len() >= Kcp2KConfig::DEFERRED_UNRELIABLE_LIMIT {
+            return Err(Kcp2KError::Congestion(format!("send_data: dropped {} bytes, too many unreliable messages waiting for the peer to authenticate", data.len())));
+        }
+        self.deferred_unreliable.value_mut().push(data.to_vec());
+        Ok(())
+    }
+
+    // 对端确认认证后发送暂存的不可靠数据
+    fn flush_deferred_unreliable(&self) {
+        if !*self.peer_confirmed || self.deferred_unreliable.is_empty() {
+            return;
+        }
+        for data in std::mem::take(self.deferred_unreliable.value_mut()) {
+            let _ = self.send_unreliable(Kcp2KUnreliableHeader::Data, &data);
+        }
+    }
+
+    // 将不可靠消息追加到本次 tick 的合并缓冲区，每条消息为 [长度 u16][消息 ID（启用去重时）][数据]
+    fn coalesce_unreliable(&self, data: &[u8]) -> Result<(), Kcp2KError> {
+        let id_size = match self.config.unreliable_dedup {
+            true => Kcp2KConfig::UNRELIABLE_MESSAGE_ID_SIZE,
+            false => 0,
+        };
+        let entry_size = Kcp2KConfig::UNRELIABLE_BATCH_LENGTH_SIZE + id_size + data.len();
+        // 合并数据报中 header 之后的可用空间
+        let capacity = self.config.unreliable_mtu() - 1;
+        // 单独就放不下的消息直接发送，先发送已合并的消息以保持顺序
+        if entry_size > capacity {
+            self.flush_unreliable_batch()?;
+            return self.send_unreliable(Kcp2KUnreliableHeader::Data, data);
+        }
+        if self.unreliable_batch.len() + entry_size > capacity {
+            self.flush_unreliable_batch()?;
+        }
+        let message_id = match id_size {
+            0 => None,
+            _ => Some(self.next_unreliable_message_id()),
+        };
+        let batch = self.unreliable_batch.value_mut();
+        batch.extend_from_slice(&((id_size + data.len()) as u16).to_le_bytes());
+        if let Some(message_id) = message_id {
+            batch.extend_from_slice(&message_id.to_le_bytes());
+        }
+        batch.extend_from_slice(data);
+        Ok(())
+    }
+
+    // 将合并缓冲区中的不可靠消息作为一个数据报发送
+    fn flush_unreliable_batch(&self) -> Result<(), Kcp2KError> {
+        if self.unreliable_batch.is_empty() {
+            return Ok(());
+        }
+        let mut batch = std::mem::take(self.unreliable_batch.value_mut());
+        let result = self.send_unreliable(Kcp2KUnreliableHeader::Batch, &batch);
+        // 保留缓冲区容量供下一个 tick 使用
+        batch.clear();
+        self.unreliable_batch.set_value(batch);
+        result
+    }
+
+    // 重试之前因为发送缓冲区已满而排队的数据
+    fn retry_pending_sends(&self) {
+        while let Some(data) = self.send_retry_queue.front() {
+            match self.raw_send_once(data) {
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    self.stats.value_mut().send_would_block += 1;
+                    break;
+                }
+                Err(e) => SendErrorInfo::record(&self.last_send_error, &e),
+            }
+            self.send_retry_queue.value_mut().pop_front();
         }
     }
 
+    // 分配下一个不可靠消息 ID，用于对端去重
+    fn next_unreliable_message_id(&self) -> u32 {
+        let message_id = *self.unreliable_message_id;
+        self.unreliable_message_id.set_value(message_id.wrapping_add(1));
+        message_id
+    }
+
     fn send_unreliable(&self, kcp2k_header_unreliable: Kcp2KUnreliableHeader, data: &[u8]) -> Result<(), Kcp2KError> {
-        // 创建一个缓冲区，用于存储消息内容
-        let mut buffer = vec![];
+        // 出站带宽耗尽时丢弃不可靠数据
+        if matches!(kcp2k_header_unreliable, Kcp2KUnreliableHeader::Data | Kcp2KUnreliableHeader::Batch) && self.out_budget().is_some_and(|tokens| tokens <= 0.0) {
+            return Err(Kcp2KError::Congestion(format!("send_unreliable: dropped {} bytes, outbound bandwidth limit reached", data.len())));
+        }
 
-        // 写入通道头部
-        buffer.push(Kcp2KChannel::Unreliable.into());
+        // 复用连接的发送缓冲区，避免每次发送都分配内存
+        let buffer = self.send_buffer.value_mut();
+        buffer.clear();
 
-        // 写入握手 cookie 以防止 UDP 欺骗
-        buffer.extend_from_slice(&self.cookie.to_le_bytes());
+        // 写入通道、握手 cookie 与 kcp 头部
+        write_unreliable_frame_header(buffer, wire_cookie(&self.fault, *self.cookie, &self.stats), kcp2k_header_unreliable);
 
-        // 写入 kcp 头部
-        buffer.push(kcp2k_header_unreliable.into());
+        // 启用去重时，为数据消息写入消息 ID
+        if self.config.unreliable_dedup && kcp2k_header_unreliable == Kcp2KUnreliableHeader::Data {
+            buffer.extend_from_slice(&self.next_unreliable_message_id().to_le_bytes());
+        }
 
         // 写入数据
         if !data.is_empty() {
@@ -397,11 +1518,130 @@ impl Kcp2kConnection {
         }
 
         //  send it raw
-        self.raw_send(&buffer)
+        self.raw_send(buffer.as_slice())
+    }
+
+    // KCP 使用的毫秒时钟，从 kcp_epoch 开始计时，不超过 KCP_CLOCK_LIMIT
+    fn kcp_clock(&self, elapsed: Duration) -> u32 {
+        elapsed.saturating_sub(*self.kcp_epoch).as_millis().min(Kcp2KConfig::KCP_CLOCK_LIMIT as u128) as u32
+    }
+
+    // 返回本次 tick 传给 KCP 的时间。KCP 的计时器用普通加法累加、用 i32 减法比较，时钟接近 2^31 毫秒时会溢出，
+    // 因此时钟超过 KCP_CLOCK_REBASE 后，在没有未确认分段时把起点移到当前时间，从 0 重新计时。
+    // 此时 KCP 中只剩旧的 ts_flush，update 发现它与当前时间相差过大会直接重置
+    fn advance_kcp_clock(&self, elapsed: Duration) -> u32 {
+        if self.kcp_clock(elapsed) >= Kcp2KConfig::KCP_CLOCK_REBASE && self.kcp.wait_snd() == 0 {
+            self.kcp_epoch.set_value(elapsed);
+        }
+        self.kcp_clock(elapsed)
+    }
+
+    // 立即刷新 KCP，将队列中的数据与确认发送出去
+    // 返回本次刷新通过 UdpOutput 写入 socket 的字节数
+    fn flush_now(&self) -> usize {
+        let bytes_sent = self.stats.bytes_sent;
+        let kcp = self.kcp.value_mut();
+        // flush 要求至少调用过一次 update
+        let _ = kcp.update(self.kcp_clock(self.watch.elapsed()));
+        let _ = kcp.flush();
+        let flushed = (self.stats.bytes_sent - bytes_sent) as usize;
+        self.consume_out_budget(flushed);
+        flushed
+    }
+
+    // 出站令牌桶容量：一秒的流量，至少能容纳一个完整的数据报
+    fn out_budget_capacity(config: &Kcp2KConfig) -> f64 {
+        match config.max_out_bps_per_conn {
+            Some(bps) => (bps as f64 / 8.0).max(config.mtu as f64),
+            None => 0.0,
+        }
+    }
+
+    // 按经过的时间补充出站令牌并返回当前剩余的字节数，未配置带宽上限时返回 None
+    fn out_budget(&self) -> Option<f64> {
+        let bps = self.config.max_out_bps_per_conn?;
+        let now = self.watch.elapsed();
+        let elapsed = now.saturating_sub(*self.out_tokens_time);
+        self.out_tokens_time.set_value(now);
+        let tokens = (*self.out_tokens + elapsed.as_secs_f64() * bps as f64 / 8.0).min(Self::out_budget_capacity(&self.config));
+        self.out_tokens.set_value(tokens);
+        Some(tokens)
+    }
+
+    // 扣除已发送的字节数，允许一次刷新透支，之后等待令牌补充
+    fn consume_out_budget(&self, bytes: usize) {
+        if self.config.max_out_bps_per_conn.is_some() {
+            self.out_tokens.set_value(*self.out_tokens - bytes as f64);
+        }
+    }
+
+    // 按 max_messages_per_sec_per_conn 限制交付的应用消息，令牌桶容量为一秒的消息数；
+    // 持续超限达到 RATE_ABUSE_DURATION 且启用 disconnect_on_rate_abuse 时断开连接
+    fn allow_incoming_message(&self) -> bool {
+        let Some(limit) = self.config.max_messages_per_sec_per_conn else {
+            return true;
+        };
+        let now = self.watch.elapsed();
+        let elapsed = now.saturating_sub(*self.in_tokens_time);
+        self.in_tokens_time.set_value(now);
+        let tokens = (*self.in_tokens + elapsed.as_secs_f64() * limit as f64).min(limit as f64);
+        if tokens >= 1.0 {
+            self.in_tokens.set_value(tokens - 1.0);
+            return true;
+        }
+        self.in_tokens.set_value(tokens);
+        self.rate_limited_messages.set_value(*self.rate_limited_messages + 1);
+
+        // 与上一次丢弃相隔不超过一秒视为持续超限
+        let since = match *self.rate_abuse.value() {
+            Some((since, last_drop)) if now.saturating_sub(last_drop) <= Duration::from_secs(1) => since,
+            _ => now,
+        };
+        self.rate_abuse.set_value(Some((since, now)));
+        if self.config.disconnect_on_rate_abuse && now.saturating_sub(since) >= Duration::from_millis(Kcp2KConfig::RATE_ABUSE_DURATION) {
+            self.on_error(Kcp2KError::Congestion(format!("Peer exceeded {} messages per second for {}ms. Disconnecting the connection.", limit, Kcp2KConfig::RATE_ABUSE_DURATION)), Kcp2KChannel::None);
+            self.on_disconnected(DisconnectReason::ProtocolError);
+        }
+        false
+    }
+
+    // 因超过接收消息速率上限被丢弃的消息数
+    pub fn rate_limited_messages(&self) -> u64 {
+        *self.rate_limited_messages
+    }
+
+    // 附加到连接上的应用数据（例如玩家 ID、认证令牌），替换之前设置的数据
+    pub fn set_user_data<T: Any + Send>(&self, data: T) {
+        self.user_data.set_value(Some(Box::new(data)));
+    }
+
+    // 在闭包中读取附加的应用数据，未设置或类型不匹配时返回 None
+    pub fn with_user_data<T: Any, R>(&self, f: impl FnOnce(&T) -> R) -> Option<R> {
+        self.with_user_data_mut(|data: &mut T| f(data))
+    }
+
+    // 在闭包中修改附加的应用数据，未设置或类型不匹配时返回 None
+    // 闭包执行期间数据被临时取出，不持有连接内部的引用：闭包中再次访问会得到 None，闭包中调用 set_user_data 设置的新数据会被保留
+    pub fn with_user_data_mut<T: Any, R>(&self, f: impl FnOnce(&mut T) -> R) -> Option<R> {
+        let mut data = self.user_data.value_mut().take()?;
+        let result = data.downcast_mut::<T>().map(f);
+        let slot = self.user_data.value_mut();
+        if slot.is_none() {
+            *slot = Some(data);
+        }
+        result
+    }
+
+    // 取出并清除附加的应用数据
+    pub fn take_user_data(&self) -> Option<Box<dyn Any + Send>> {
+        self.user_data.value_mut().take()
     }
 
     // 处理 ping
     fn handle_ping(&self, elapsed_time: Duration) {
+        if !self.config.auto_ping {
+            return;
+        }
         if elapsed_time >= *self.last_send_ping_time + Duration::from_millis(Kcp2KConfig::PING_INTERVAL) {
             self.last_send_ping_time.set_value(elapsed_time);
             self.send_ping();
@@ -410,50 +1650,60 @@ impl Kcp2kConnection {
 
     // 处理超时
     fn handle_timeout(&self, elapsed_time: Duration) {
-        if elapsed_time > *self.last_recv_time + Duration::from_millis(self.config.timeout) {
-            self.on_error(Kcp2KError::Timeout("timeout to disconnected.".to_string()));
-            self.on_disconnected();
+        let deadline = *self.last_recv_time + Duration::from_millis(self.config.timeout);
+        if elapsed_time <= deadline {
+            return;
+        }
+        // 已认证的连接启用宽限期时先进入 Suspect 状态，宽限期结束仍未收到数据包才断开
+        match self.state.value() {
+            Kcp2KConnectionStates::Authenticated if self.config.disconnect_grace_ms > 0 => {
+                self.state.set_value(Kcp2KConnectionStates::Suspect);
+                self.on_suspect();
+                return;
+            }
+            Kcp2KConnectionStates::Suspect if elapsed_time <= deadline + Duration::from_millis(self.config.disconnect_grace_ms) => return,
+            _ => {}
         }
+        self.on_error(Kcp2KError::Timeout("timeout to disconnected.".to_string()), Kcp2KChannel::None);
+        self.on_disconnected(DisconnectReason::Timeout);
     }
 
     // 处理 dead_link
     fn handle_dead_link(&self) {
         if self.kcp.is_dead_link() {
-            self.on_error(Kcp2KError::Timeout("dead link to disconnecting.".to_string()));
-            self.on_disconnected();
+            self.on_error(Kcp2KError::Timeout("dead link to disconnecting.".to_string()), Kcp2KChannel::Reliable);
+            self.on_disconnected(DisconnectReason::DeadLink);
         }
     }
 
     // 处理可靠消息
-    fn raw_input_reliable(&self, data: &[u8]) -> Result<(), Kcp2KError> {
+    fn raw_input_reliable(&self, data: &[u8], elapsed_time: Duration) -> Result<(), Kcp2KError> {
+        self.inspect_segments(data, elapsed_time);
+        // 服务器在认证之前只接收有限数量的分段，超过则在交给 KCP 缓存之前断开，KCP 在认证前缓存的分段数因此不超过 max_preauth_segments。
+        // 断开后同一次 tick 中到达的数据包也不再交给 KCP。这是资源上限而不是格式错误，不受 lenient_invalid_receive 影响
+        if *self.kcp2k_mode == Kcp2KMode::Server && !self.is_authenticated() && *self.preauth_segments > self.config.max_preauth_segments {
+            let err = Kcp2KError::InvalidReceive(format!("{}: Received {} reliable segments before authentication, the limit is {}. Disconnecting the connection.", std::any::type_name::<Self>(), *self.preauth_segments, self.config.max_preauth_segments));
+            if *self.state == Kcp2KConnectionStates::Connected {
+                self.on_error(err.clone(), Kcp2KChannel::Reliable);
+                self.on_disconnected(DisconnectReason::ProtocolError);
+            }
+            return Err(err);
+        }
         if let Err(e) = self.kcp.value_mut().input(&data) {
             let err = Kcp2KError::InvalidReceive(format!("[KCP2K] {}: Input failed with error={:?} for buffer with length={}", std::any::type_name::<Self>(), e, data.len() - 1));
-            self.on_error(err.clone());
+            self.handle_receive_error(err.clone(), Kcp2KChannel::Reliable);
             return Err(err);
         }
         Ok(())
     }
 
     // 处理不可靠消息
-    fn raw_input_unreliable(&self, data: &[u8]) -> Result<(), Kcp2KError> {
-        // 至少需要一个字节用于 header
-        if data.len() < 1 {
-            return Err(Kcp2KError::InvalidReceive(format!("{}: Received unreliable message with invalid length={}. Disconnecting the connection.", std::any::type_name::<Self>(), data.len())));
-        }
-        // 安全地提取标头。攻击者可能会发送超出枚举范围的值。
-        let header = data[0];
-
-        // 判断 header 类型
-        let header = Kcp2KUnreliableHeader::from(header);
-
-        // 提取数据
-        let data = &data[1..];
-
+    fn raw_input_unreliable(&self, header: Kcp2KUnreliableHeader, data: &[u8]) -> Result<(), Kcp2KError> {
         // 根据头部类型处理消息
         match header {
             Kcp2KUnreliableHeader::Data => match self.state.value() {
                 Kcp2KConnectionStates::Authenticated => {
-                    self.on_data(data, Kcp2KChannel::Unreliable);
+                    self.deliver_unreliable(data);
                     Ok(())
                 }
                 _ => {
@@ -470,19 +1720,115 @@ impl Kcp2kConnection {
                     Ok(())
                 }
             },
+            Kcp2KUnreliableHeader::Batch => match self.state.value() {
+                Kcp2KConnectionStates::Authenticated => self.split_unreliable_batch(data),
+                // 与 Data 相同，认证之前的不可靠消息直接忽略
+                _ => Ok(()),
+            },
             Kcp2KUnreliableHeader::Disconnect => {
-                self.on_disconnected();
+                self.on_disconnected(DisconnectReason::PeerRequested);
                 Ok(())
             }
-            Kcp2KUnreliableHeader::Ping => Ok(()),
+            Kcp2KUnreliableHeader::Reject => {
+                // 只有尚未认证的客户端接受拒绝，已认证的连接忽略，避免被伪造的数据包断开
+                if *self.kcp2k_mode == Kcp2KMode::Client && *self.state == Kcp2KConnectionStates::Connected && !data.is_empty() {
+                    self.on_rejected(data[0], &data[1..]);
+                }
+                Ok(())
+            }
+            Kcp2KUnreliableHeader::Ping => {
+                self.on_ping(data, Kcp2KChannel::Unreliable);
+                Ok(())
+            }
+            Kcp2KUnreliableHeader::Pong => {
+                self.on_pong(data);
+                Ok(())
+            }
+        }
+    }
+
+    // 将一条不可靠数据消息交给应用层，启用去重时丢弃窗口内已经收到过的消息
+    fn deliver_unreliable(&self, data: &[u8]) {
+        let data = match self.config.unreliable_dedup {
+            true => match self.dedup_unreliable(data) {
+                Some(data) => data,
+                None => return,
+            },
+            false => data,
+        };
+        self.on_data(data, Kcp2KChannel::Unreliable);
+    }
+
+    // 拆分合并的不可靠消息，每条消息为 [长度 u16][消息]
+    fn split_unreliable_batch(&self, data: &[u8]) -> Result<(), Kcp2KError> {
+        let mut rest = data;
+        while !rest.is_empty() {
+            if rest.len() < Kcp2KConfig::UNRELIABLE_BATCH_LENGTH_SIZE {
+                return Err(Kcp2KError::InvalidReceive(format!("{}: Received truncated unreliable batch.", std::any::type_name::<Self>())));
+            }
+            let len = u16::from_le_bytes([rest[0], rest[1]]) as usize;
+            let end = Kcp2KConfig::UNRELIABLE_BATCH_LENGTH_SIZE + len;
+            if rest.len() < end {
+                return Err(Kcp2KError::InvalidReceive(format!("{}: Received unreliable batch entry with length={} but only {} bytes left.", std::any::type_name::<Self>(), len, rest.len() - Kcp2KConfig::UNRELIABLE_BATCH_LENGTH_SIZE)));
+            }
+            self.deliver_unreliable(&rest[Kcp2KConfig::UNRELIABLE_BATCH_LENGTH_SIZE..end]);
+            rest = &rest[end..];
+        }
+        Ok(())
+    }
+
+    // 不可靠消息去重，返回去掉消息 ID 后的数据；重复或过短的消息返回 None
+    fn dedup_unreliable<'a>(&self, data: &'a [u8]) -> Option<&'a [u8]> {
+        if data.len() < Kcp2KConfig::UNRELIABLE_MESSAGE_ID_SIZE {
+            return None;
+        }
+        let message_id = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+
+        let seen = self.unreliable_seen.value_mut();
+        if seen.contains(&message_id) {
+            return None;
+        }
+        if seen.len() >= Kcp2KConfig::UNRELIABLE_DEDUP_WINDOW {
+            seen.pop_front();
+        }
+        seen.push_back(message_id);
+        self.record_unreliable_id(message_id);
+
+        Some(&data[Kcp2KConfig::UNRELIABLE_MESSAGE_ID_SIZE..])
+    }
+
+    // 记录收到的不可靠消息 ID 范围与数量，用于推算不可靠通道的丢包率
+    fn record_unreliable_id(&self, message_id: u32) {
+        let range = match *self.unreliable_id_range.value() {
+            None => (message_id, message_id),
+            Some((first, highest)) => {
+                let first = if (message_id.wrapping_sub(first) as i32) < 0 { message_id } else { first };
+                let highest = if message_id.wrapping_sub(highest) as i32 > 0 { message_id } else { highest };
+                (first, highest)
+            }
+        };
+        self.unreliable_id_range.set_value(Some(range));
+        self.unreliable_received_ids.set_value(*self.unreliable_received_ids + 1);
+    }
+
+    // 校验可靠消息内的 cookie。与外层 cookie 一样只在认证后校验；
+    // 客户端在收到服务器的 cookie 之前发送的 Hello 与 Ping 中 cookie 为 0，它们可能在认证后才被重传到达，因此允许
+    fn verify_reliable_cookie(&self, header: Kcp2KReliableHeader, data: &[u8]) -> bool {
+        if data.len() < Kcp2KConfig::COOKIE_HEADER_SIZE {
+            return false;
+        }
+        if !matches!(self.state.value(), Kcp2KConnectionStates::Authenticated | Kcp2KConnectionStates::Suspect) {
+            return true;
         }
+        let message_cookie = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+        message_cookie == *self.cookie || (message_cookie == 0 && matches!(header, Kcp2KReliableHeader::Hello | Kcp2KReliableHeader::Ping))
     }
 
     // 接收下一个可靠消息
     fn receive_next_reliable(&self) -> Option<(Kcp2KReliableHeader, Vec<u8>)> {
         // 用于存储接收到的数据
         let mut buffer = Vec::new();
-        // 初始化 buffer 大小
+        // 初始化 buffer 大小，peeksize 失败表示暂时没有完整的消息可读
         match self.kcp.peeksize() {
             Ok(size) => {
                 buffer.resize(size, 0);
@@ -494,20 +1840,38 @@ impl Kcp2kConnection {
         // 从 KCP 接收数据
         match self.kcp.value_mut().recv(&mut buffer) {
             Ok(size) => {
+                // 每条可靠消息至少包含 1 字节的头部，负载为空的 Hello/Ping/Data 长度为 1，
+                // 因此长度为 0 只可能是格式错误的消息，而不是没有数据
                 if size == 0 {
-                    self.on_error(Kcp2KError::InvalidReceive(format!("{}: Receive failed with error={}. closing connection.", std::any::type_name::<Self>(), size)));
-                    self.send_disconnect();
+                    self.handle_receive_error(Kcp2KError::InvalidReceive(format!("{}: Received reliable message without header byte.", std::any::type_name::<Self>())), Kcp2KChannel::Reliable);
                     return None;
                 }
-                // 解析头部
-                let header_byte = buffer[0];
+                // 安全地解析头部，攻击者可能会发送超出枚举范围的值
+                let header = match Kcp2KReliableHeader::try_from(buffer[0]) {
+                    Ok(header) => header,
+                    Err(e) => {
+                        self.handle_unknown_header(Kcp2KError::InvalidReceive(format!("{}: Receive failed to parse header: {}.", std::any::type_name::<Self>(), e)), Kcp2KChannel::Reliable);
+                        return None;
+                    }
+                };
+
+                // 严格模式下校验消息内的 cookie
+                let data_start = match self.config.strict_cookie_on_reliable {
+                    true => {
+                        if !self.verify_reliable_cookie(header, &buffer[1..size]) {
+                            self.handle_receive_error(Kcp2KError::InvalidReceive(format!("{}: Received reliable message {:?} with invalid cookie.", std::any::type_name::<Self>(), header)), Kcp2KChannel::Reliable);
+                            return None;
+                        }
+                        1 + Kcp2KConfig::COOKIE_HEADER_SIZE
+                    }
+                    false => 1,
+                };
 
                 // 从 buffer 中提取消息
-                Some((Kcp2KReliableHeader::from(header_byte), buffer[1..size].to_vec()))
+                Some((header, buffer[data_start..size].to_vec()))
             }
             Err(error) => {
-                self.on_error(Kcp2KError::InvalidReceive(format!("[KCP-2K] connection - {}: Receive failed with error={}. closing connection.", std::any::type_name::<Self>(), error)));
-                self.send_disconnect();
+                self.handle_receive_error(Kcp2KError::InvalidReceive(format!("[KCP-2K] connection - {}: Receive failed with error={}.", std::any::type_name::<Self>(), error)), Kcp2KChannel::Reliable);
                 None
             }
         }
@@ -519,20 +1883,60 @@ impl Kcp2kConnection {
         self.handle_dead_link();
         self.handle_ping(elapsed_time);
 
-        if let Some((header, _)) = self.receive_next_reliable() {
+        // 故障注入：延迟的认证到期
+        #[cfg(any(test, feature = "testing"))]
+        if let Some(authenticate_at) = *self.pending_authentication.value()
+            && elapsed_time >= authenticate_at
+        {
+            self.pending_authentication.set_value(None);
+            self.on_authenticated();
+            return;
+        }
+
+        if let Some((header, data)) = self.receive_next_reliable() {
             match header {
                 Kcp2KReliableHeader::Hello => {
+                    // 故障注入：收到 Hello 后延迟认证
+                    #[cfg(any(test, feature = "testing"))]
+                    if let Some(delay) = self.fault.delay_authentication
+                        && *self.kcp2k_mode == Kcp2KMode::Server
+                    {
+                        self.pending_authentication.set_value(Some(elapsed_time + delay));
+                        return;
+                    }
+                    // 客户端解析服务器分配的连接 ID，旧版本服务器的 Hello 负载为空
+                    if *self.kcp2k_mode == Kcp2KMode::Client && data.len() >= 8 {
+                        self.assigned_id.set_value(Some(ConnId(u64::from_le_bytes([data[0], data[1], data[2], data[3], data[4], data[5], data[6], data[7]]))));
+                    }
                     self.on_authenticated();
                 }
-                Kcp2KReliableHeader::Data => {
-                    self.on_error(Kcp2KError::InvalidReceive("Received invalid header while Connected. Disconnecting the connection.".to_string()));
-                    self.on_disconnected();
+                Kcp2KReliableHeader::Challenge => {
+                    self.on_challenge(&data);
+                }
+                Kcp2KReliableHeader::Ping => {
+                    self.on_ping(&data, Kcp2KChannel::Reliable);
+                }
+                Kcp2KReliableHeader::Pong => {
+                    self.on_pong(&data);
+                }
+                Kcp2KReliableHeader::Data | Kcp2KReliableHeader::DataWithReceipt | Kcp2KReliableHeader::TaggedData => {
+                    self.handle_receive_error(Kcp2KError::InvalidReceive(format!("Received {:?} while Connected.", header)), Kcp2KChannel::Reliable);
+                }
+                Kcp2KReliableHeader::Disconnect => {
+                    self.on_disconnected(DisconnectReason::PeerRequested);
                 }
                 _ => {}
             }
         }
     }
 
+    // 处理断开宽限期中的连接：只等待对端的数据包或宽限期结束，收到数据包时在 raw_input 中恢复
+    fn tick_incoming_suspect(&self, elapsed_time: Duration) {
+        self.handle_timeout(elapsed_time);
+        self.handle_dead_link();
+        self.handle_ping(elapsed_time);
+    }
+
     // 处理认证过的连接
     fn tick_incoming_authenticated(&self, elapsed_time: Duration) {
         self.handle_timeout(elapsed_time);
@@ -542,19 +1946,1202 @@ impl Kcp2kConnection {
         if let Some((header, data)) = self.receive_next_reliable() {
             match header {
                 Kcp2KReliableHeader::Hello => {
-                    self.on_error(Kcp2KError::InvalidReceive("Received invalid header while Authenticated. Disconnecting the connection.".to_string()));
-                    self.on_disconnected();
+                    // 认证后再次收到 Hello 说明对端重传了握手消息；cookie 已在 raw_input 中校验，错误的 cookie 会在那里断开连接，因此这里忽略即可
+                    warn!(instance = self.config.instance_name.as_deref(); "{} Ignored duplicate Hello from {} while Authenticated.", self.config.log_prefix(), self.remote_address());
                 }
                 Kcp2KReliableHeader::Data => {
+                    // send_data 拒绝发送空消息，空的 Data 只可能来自不遵守协议的对端，按协议错误处理。
+                    // 只有头部、没有负载的 Hello/Ping 不经过这里，不会因此断开
                     if data.is_empty() {
-                        self.on_error(Kcp2KError::InvalidReceive("Received empty Data message while Authenticated. Disconnecting the connection.".to_string()));
-                        self.on_disconnected();
+                        self.handle_receive_error(Kcp2KError::InvalidReceive("Received empty Data message while Authenticated.".to_string()), Kcp2KChannel::Reliable);
                     } else {
                         self.on_data(&data, Kcp2KChannel::Reliable);
                     }
                 }
+                Kcp2KReliableHeader::DataWithReceipt => {
+                    if data.len() <= 4 {
+                        self.handle_receive_error(Kcp2KError::InvalidReceive("Received invalid DataWithReceipt message while Authenticated.".to_string()), Kcp2KChannel::Reliable);
+                    } else {
+                        // 先交给应用层，再回复回执
+                        self.on_data(&data[4..], Kcp2KChannel::Reliable);
+                        let _ = self.send_reliable(Kcp2KReliableHeader::Receipt, &data[..4]);
+                    }
+                }
+                Kcp2KReliableHeader::TaggedData => {
+                    if data.len() <= 1 {
+                        self.handle_receive_error(Kcp2KError::InvalidReceive("Received invalid TaggedData message while Authenticated.".to_string()), Kcp2KChannel::Reliable);
+                    } else {
+                        self.on_tagged_data(&data[1..], Kcp2KChannel::Reliable, data[0]);
+                    }
+                }
+                Kcp2KReliableHeader::Receipt if data.len() >= 4 => {
+                    self.on_receipt(u32::from_le_bytes([data[0], data[1], data[2], data[3]]));
+                }
+                Kcp2KReliableHeader::Challenge => {
+                    self.on_challenge(&data);
+                }
+                Kcp2KReliableHeader::Ping => {
+                    self.on_ping(&data, Kcp2KChannel::Reliable);
+                }
+                Kcp2KReliableHeader::Pong => {
+                    self.on_pong(&data);
+                }
+                Kcp2KReliableHeader::Disconnect => {
+                    self.on_disconnected(DisconnectReason::PeerRequested);
+                }
                 _ => {}
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use socket2::{Domain, Protocol, Type};
+    use std::mem::MaybeUninit;
+    use std::net::SocketAddr;
+
+    fn noop_callback(_: &Kcp2kConnection, _: Callback) {}
+
+    fn shared_callback(callback: fn(&Kcp2kConnection, Callback)) -> Arc<CallbackFuncType> {
+        Arc::new(Box::new(callback))
+    }
+
+    // 绑定一个本地回环 UDP socket
+    fn bind_socket() -> Socket {
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP)).unwrap();
+        let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        socket.bind(&addr.into()).unwrap();
+        socket.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+        socket
+    }
+
+    // 接收 socket 上所有待处理的数据报
+    fn receive_all(socket: &Socket) -> Vec<Vec<u8>> {
+        let mut datagrams = Vec::new();
+        loop {
+            let mut buf = vec![MaybeUninit::<u8>::uninit(); 2048];
+            match socket.recv(&mut buf) {
+                Ok(size) => datagrams.push(buf[..size].iter().map(|b| unsafe { b.assume_init() }).collect()),
+                Err(_) => break,
+            }
+        }
+        datagrams
+    }
+
+    // 创建一个服务器模式的连接，对端为 peer
+    fn server_connection(socket: &Arc<Socket>, peer: &Socket) -> Kcp2kConnection {
+        let peer_addr = peer.local_addr().unwrap();
+        Kcp2kConnection::new(ConnId(1), Arc::new(Kcp2KConfig::default()), Arc::new(Kcp2KMode::Server), socket.clone(), Arc::new(peer_addr), shared_callback(noop_callback), Default::default())
+    }
+
+    // 创建一对互为对端的服务器模式连接，返回 (socket_a, socket_b, conn_a, conn_b)
+    fn connected_pair() -> (Arc<Socket>, Arc<Socket>, Kcp2kConnection, Kcp2kConnection) {
+        connected_pair_with(Kcp2KConfig::default(), noop_callback)
+    }
+
+    // 同 connected_pair，两端使用同一份配置，conn_b 使用给定的回调
+    fn connected_pair_with(config: Kcp2KConfig, callback_b: fn(&Kcp2kConnection, Callback)) -> (Arc<Socket>, Arc<Socket>, Kcp2kConnection, Kcp2kConnection) {
+        let socket_a = Arc::new(bind_socket());
+        let socket_b = Arc::new(bind_socket());
+        let conn_a = Kcp2kConnection::new(ConnId(1), Arc::new(config.clone()), Arc::new(Kcp2KMode::Server), socket_a.clone(), Arc::new(socket_b.local_addr().unwrap()), shared_callback(noop_callback), Default::default());
+        let conn_b = Kcp2kConnection::new(ConnId(2), Arc::new(config), Arc::new(Kcp2KMode::Server), socket_b.clone(), Arc::new(socket_a.local_addr().unwrap()), shared_callback(callback_b), Default::default());
+        (socket_a, socket_b, conn_a, conn_b)
+    }
+
+    // 把 socket 上待处理的所有数据报交给 connection，每个都必须被接受
+    fn deliver(socket: &Socket, connection: &mut Kcp2kConnection) {
+        for datagram in receive_all(socket) {
+            assert!(connection.raw_input(&datagram).is_ok());
+        }
+    }
+
+    #[test]
+    fn raw_input_rejects_wrong_cookie() {
+        let socket = Arc::new(bind_socket());
+        let peer = bind_socket();
+        let mut connection = server_connection(&socket, &peer);
+        connection.set_cookie(0x1234_5678);
+        connection.state.set_value(Kcp2KConnectionStates::Authenticated);
+
+        let mut segment: Vec<u8> = vec![Kcp2KChannel::Unreliable.into()];
+        segment.extend_from_slice(&0x8765_4321u32.to_le_bytes());
+        segment.push(Kcp2KUnreliableHeader::Ping.into());
+        segment.push(0);
+
+        let result = connection.raw_input(&segment);
+        assert!(matches!(result, Err(Kcp2KError::InvalidReceive(_))));
+        assert_eq!(*connection.cookie, 0x1234_5678);
+    }
+
+    #[test]
+    fn empty_payload_reliable_message_is_not_a_receive_failure() {
+        let (_, socket_b, conn_a, mut conn_b) = connected_pair();
+
+        assert!(conn_a.send_reliable(Kcp2KReliableHeader::Ping, &[]).is_ok());
+        assert!(conn_a.send_reliable(Kcp2KReliableHeader::Data, &[]).is_ok());
+        conn_a.tick_outgoing();
+
+        deliver(&socket_b, &mut conn_b);
+
+        assert!(matches!(conn_b.receive_next_reliable(), Some((Kcp2KReliableHeader::Ping, data)) if data.is_empty()));
+        assert!(matches!(conn_b.receive_next_reliable(), Some((Kcp2KReliableHeader::Data, data)) if data.is_empty()));
+        assert!(conn_b.receive_next_reliable().is_none());
+        assert_eq!(*conn_b.state, Kcp2KConnectionStates::Connected);
+    }
+
+    #[test]
+    fn empty_reliable_data_is_a_protocol_error() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        static PROTOCOL_ERRORS: AtomicUsize = AtomicUsize::new(0);
+        fn record_disconnect(_: &Kcp2kConnection, callback: Callback) {
+            if matches!(callback.r#type, CallbackType::OnDisconnected) && callback.disconnect_reason == DisconnectReason::ProtocolError {
+                PROTOCOL_ERRORS.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        let (_, socket_b, conn_a, mut conn_b) = connected_pair_with(Kcp2KConfig::default(), record_disconnect);
+        conn_a.set_cookie(0x1234_5678);
+        conn_b.set_cookie(0x1234_5678);
+        conn_a.state.set_value(Kcp2KConnectionStates::Authenticated);
+        conn_b.state.set_value(Kcp2KConnectionStates::Authenticated);
+
+        // send_data 在本地拒绝空消息，不会发出也不会断开
+        assert!(matches!(conn_a.send_data(&[], Kcp2KChannel::Reliable), Err(Kcp2KError::InvalidSend(_))));
+        assert_eq!(*conn_a.state, Kcp2KConnectionStates::Authenticated);
+
+        // 只有头部的 Ping 不会断开连接
+        assert!(conn_a.send_reliable(Kcp2KReliableHeader::Ping, &[]).is_ok());
+        conn_a.flush_now();
+        deliver(&socket_b, &mut conn_b);
+        conn_b.tick_incoming();
+        assert_eq!(*conn_b.state, Kcp2KConnectionStates::Authenticated);
+
+        // 绕过 send_data 发送的空 Data 按协议错误断开
+        assert!(conn_a.send_reliable(Kcp2KReliableHeader::Data, &[]).is_ok());
+        conn_a.flush_now();
+        deliver(&socket_b, &mut conn_b);
+        conn_b.tick_incoming();
+        assert_eq!(*conn_b.state, Kcp2KConnectionStates::Disconnected);
+        assert_eq!(PROTOCOL_ERRORS.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn set_remote_addr_redirects_subsequent_sends() {
+        let socket = Arc::new(bind_socket());
+        let new_socket = Arc::new(bind_socket());
+        let old_peer = bind_socket();
+        let new_peer = bind_socket();
+        let connection = server_connection(&socket, &old_peer);
+        let other = server_connection(&socket, &old_peer);
+
+        connection.set_remote_addr(new_socket.clone(), new_peer.local_addr().unwrap());
+
+        // 不可靠消息经由 raw_send 发送
+        assert!(connection.send_unreliable(Kcp2KUnreliableHeader::Ping, &[]).is_ok());
+        // 可靠消息经由 UdpOutput 发送
+        assert!(connection.send_reliable(Kcp2KReliableHeader::Ping, &[]).is_ok());
+        connection.tick_outgoing();
+
+        // 两种消息都从新的 socket 发往新地址
+        let mut channels = Vec::new();
+        let mut buf = [MaybeUninit::<u8>::uninit(); 2048];
+        while let Ok((_, from)) = new_peer.recv_from(&mut buf) {
+            assert_eq!(from, new_socket.local_addr().unwrap());
+            channels.push(unsafe { buf[0].assume_init() });
+        }
+        assert!(channels.contains(&(Kcp2KChannel::Unreliable as u8)));
+        assert!(channels.contains(&(Kcp2KChannel::Reliable as u8)));
+        assert!(receive_all(&old_peer).is_empty());
+
+        // 共享的 socket 没有被替换，其他连接仍然使用原来的 socket
+        assert_eq!(other.socket.local_addr().unwrap(), socket.local_addr().unwrap());
+    }
+
+    #[test]
+    fn unreliable_messages_use_the_full_datagram_budget() {
+        let socket = Arc::new(bind_socket());
+        let peer = bind_socket();
+        let connection = server_connection(&socket, &peer);
+        connection.state.set_value(Kcp2KConnectionStates::Authenticated);
+        connection.peer_confirmed.set_value(true);
+
+        let max_size = connection.unreliable_max_size();
+        assert_eq!(max_size, Kcp2KConfig::default().mtu - Kcp2KConfig::METADATA_SIZE_UNRELIABLE - 1);
+        assert!(connection.send_data(&vec![1; max_size], Kcp2KChannel::Unreliable).is_ok());
+        assert!(matches!(connection.send_data(&vec![1; max_size + 1], Kcp2KChannel::Unreliable), Err(Kcp2KError::InvalidSend(_))));
+
+        let datagrams = receive_all(&peer);
+        assert_eq!(datagrams.len(), 1);
+        assert_eq!(datagrams[0].len(), Kcp2KConfig::default().mtu);
+    }
+
+    #[test]
+    fn duplicate_hello_after_authentication_is_ignored() {
+        let (_, socket_b, conn_a, mut conn_b) = connected_pair();
+        conn_a.set_cookie(0x1234_5678);
+        conn_b.set_cookie(0x1234_5678);
+        conn_b.state.set_value(Kcp2KConnectionStates::Authenticated);
+
+        // 对端重传的 Hello，cookie 正确
+        conn_a.send_hello();
+        conn_a.tick_outgoing();
+        deliver(&socket_b, &mut conn_b);
+        conn_b.tick_incoming();
+
+        assert_eq!(*conn_b.state, Kcp2KConnectionStates::Authenticated);
+    }
+
+    #[test]
+    fn ping_is_answered_with_pong() {
+        let (socket_a, socket_b, mut conn_a, mut conn_b) = connected_pair();
+
+        assert!(conn_a.ping().is_ok());
+        conn_a.tick_outgoing();
+        deliver(&socket_b, &mut conn_b);
+        conn_b.tick_incoming();
+        conn_b.tick_outgoing();
+        deliver(&socket_a, &mut conn_a);
+        conn_a.tick_incoming();
+
+        assert!(conn_a.last_ping_rtt().is_some());
+    }
+
+    #[test]
+    fn ping_without_timestamp_is_not_answered() {
+        let (socket_a, socket_b, mut conn_a, mut conn_b) = connected_pair();
+
+        // 旧版本对端的 ping 只携带 RTT 估计值
+        assert!(conn_a.send_reliable(Kcp2KReliableHeader::Ping, &42u32.to_le_bytes()).is_ok());
+        conn_a.tick_outgoing();
+        deliver(&socket_b, &mut conn_b);
+        conn_b.tick_incoming();
+        conn_b.tick_outgoing();
+        deliver(&socket_a, &mut conn_a);
+
+        assert_eq!(conn_b.peer_rtt_ms(), Some(42));
+        assert!(conn_a.receive_next_reliable().is_none());
+        assert!(conn_a.last_ping_rtt().is_none());
+    }
+
+    #[test]
+    fn send_data_before_authentication_is_rejected_locally() {
+        let socket = Arc::new(bind_socket());
+        let peer = bind_socket();
+        let connection = server_connection(&socket, &peer);
+
+        assert!(matches!(connection.send_data(b"hello", Kcp2KChannel::Reliable), Err(Kcp2KError::ConnectionClosed(_))));
+        assert!(matches!(connection.send_data(b"hello", Kcp2KChannel::Unreliable), Err(Kcp2KError::ConnectionClosed(_))));
+        assert!(receive_all(&peer).is_empty());
+    }
+
+    #[test]
+    fn discard_pending_recv_empties_the_receive_queue() {
+        let (_, socket_b, conn_a, mut conn_b) = connected_pair();
+
+        for _ in 0..3 {
+            assert!(conn_a.send_reliable(Kcp2KReliableHeader::Data, b"stale").is_ok());
+        }
+        conn_a.tick_outgoing();
+        deliver(&socket_b, &mut conn_b);
+
+        assert_eq!(conn_b.discard_pending_recv(), 3);
+        assert!(conn_b.receive_next_reliable().is_none());
+    }
+
+    #[test]
+    fn fault_injection_drops_hello_response() {
+        let (socket_a, socket_b, mut conn_a, mut conn_b) = connected_pair();
+        conn_b.set_fault_injection(FaultConfig { drop_hello_response: true, ..Default::default() });
+
+        conn_a.send_hello();
+        conn_a.tick_outgoing();
+        deliver(&socket_b, &mut conn_b);
+        conn_b.tick_incoming();
+        conn_b.tick_outgoing();
+        deliver(&socket_a, &mut conn_a);
+
+        assert_eq!(*conn_b.state, Kcp2KConnectionStates::Authenticated);
+        assert!(conn_a.receive_next_reliable().is_none());
+    }
+
+    #[test]
+    fn unreliable_sent_on_connect_waits_for_the_client_to_authenticate() {
+        let server_socket = Arc::new(bind_socket());
+        let client_socket = Arc::new(bind_socket());
+        client_socket.connect(&server_socket.local_addr().unwrap()).unwrap();
+        let mut server = server_connection(&server_socket, &client_socket);
+        let mut client = Kcp2kConnection::new(ConnId(2), Arc::new(Kcp2KConfig::default()), Arc::new(Kcp2KMode::Client), client_socket.clone(), Arc::new(client_socket.local_addr().unwrap()), shared_callback(noop_callback), Default::default());
+
+        // 服务器认证后立即发送不可靠的欢迎消息（模拟在 OnConnected 中发送）
+        server.on_authenticated();
+        assert!(server.send_data(b"welcome", Kcp2KChannel::Unreliable).is_ok());
+        server.tick_outgoing();
+
+        // 客户端先只收到 Hello，不可靠消息被暂存
+        let datagrams = receive_all(&client_socket);
+        assert!(datagrams.iter().all(|d| d[0] == Kcp2KChannel::Reliable as u8));
+        for datagram in datagrams {
+            assert!(client.raw_input(&datagram).is_ok());
+        }
+        client.tick_incoming();
+        assert_eq!(*client.state, Kcp2KConnectionStates::Authenticated);
+        client.tick_outgoing();
+
+        // 服务器收到 Hello 的确认后发送暂存的不可靠消息
+        deliver(&server_socket, &mut server);
+        server.tick_outgoing();
+        let datagrams = receive_all(&client_socket);
+        assert!(datagrams.iter().any(|d| d[0] == Kcp2KChannel::Unreliable as u8));
+        for datagram in datagrams {
+            assert!(client.raw_input(&datagram).is_ok());
+        }
+        assert_eq!(*client.state, Kcp2KConnectionStates::Authenticated);
+    }
+
+    #[test]
+    fn callback_can_capture_state() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let socket = Arc::new(bind_socket());
+        let peer = bind_socket();
+        let callback: CallbackFuncType = Box::new(move |_, callback| {
+            tx.send(callback.data).unwrap();
+        });
+        let connection = Kcp2kConnection::new(ConnId(1), Arc::new(Kcp2KConfig::default()), Arc::new(Kcp2KMode::Server), socket.clone(), Arc::new(peer.local_addr().unwrap()), Arc::new(callback), Default::default());
+
+        connection.on_data(b"first", Kcp2KChannel::Reliable);
+        connection.on_data(b"second", Kcp2KChannel::Unreliable);
+        assert_eq!(rx.try_recv().unwrap(), b"first");
+        assert_eq!(rx.try_recv().unwrap(), b"second");
+    }
+
+    #[test]
+    fn callback_can_reenter_the_connection() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let socket = Arc::new(bind_socket());
+        let peer = bind_socket();
+        // 在回调中断开连接，disconnect 会同步触发 OnDisconnected，再次进入同一个回调
+        let callback: CallbackFuncType = Box::new(move |conn, callback| {
+            let is_data = matches!(callback.r#type, CallbackType::OnData);
+            tx.send(callback.r#type).unwrap();
+            if is_data {
+                conn.disconnect();
+            }
+        });
+        let connection = Kcp2kConnection::new(ConnId(1), Arc::new(Kcp2KConfig::default()), Arc::new(Kcp2KMode::Server), socket.clone(), Arc::new(peer.local_addr().unwrap()), Arc::new(callback), Default::default());
+        connection.state.set_value(Kcp2KConnectionStates::Authenticated);
+
+        connection.on_data(b"quit", Kcp2KChannel::Reliable);
+        assert!(matches!(rx.try_recv().unwrap(), CallbackType::OnData));
+        assert!(matches!(rx.try_recv().unwrap(), CallbackType::OnDisconnected));
+        assert_eq!(connection.state(), Kcp2KConnectionStates::Disconnected);
+    }
+
+    #[test]
+    fn disconnect_notifies_peer_and_reports_kicked() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let socket = Arc::new(bind_socket());
+        let peer = bind_socket();
+        let callback: CallbackFuncType = Box::new(move |_, callback| {
+            if matches!(callback.r#type, CallbackType::OnDisconnected) {
+                tx.send(callback.disconnect_reason).unwrap();
+            }
+        });
+        let connection = Kcp2kConnection::new(ConnId(1), Arc::new(Kcp2KConfig::default()), Arc::new(Kcp2KMode::Server), socket.clone(), Arc::new(peer.local_addr().unwrap()), Arc::new(callback), Default::default());
+        connection.state.set_value(Kcp2KConnectionStates::Authenticated);
+
+        assert!(connection.is_authenticated());
+        connection.disconnect();
+        assert_eq!(connection.state(), Kcp2KConnectionStates::Disconnected);
+        assert!(!connection.is_authenticated());
+        assert_eq!(rx.try_recv().unwrap(), DisconnectReason::Kicked);
+        let datagrams = receive_all(&peer);
+        assert!(!datagrams.is_empty());
+        assert!(datagrams.iter().all(|d| d[0] == Kcp2KChannel::Unreliable as u8 && d[5] == Kcp2KUnreliableHeader::Disconnect as u8));
+
+        // 重复调用不会再次触发回调
+        connection.disconnect();
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn user_data_is_readable_and_writable_from_the_callback() {
+        struct Player {
+            id: u32,
+            messages: u32,
+        }
+        let socket = Arc::new(bind_socket());
+        let peer = bind_socket();
+        let callback: CallbackFuncType = Box::new(|conn, callback| {
+            if matches!(callback.r#type, CallbackType::OnData) {
+                conn.with_user_data_mut(|player: &mut Player| player.messages += 1);
+            }
+        });
+        let connection = Kcp2kConnection::new(ConnId(1), Arc::new(Kcp2KConfig::default()), Arc::new(Kcp2KMode::Server), socket.clone(), Arc::new(peer.local_addr().unwrap()), Arc::new(callback), Default::default());
+        assert!(connection.with_user_data(|_: &Player| ()).is_none());
+
+        connection.set_user_data(Player { id: 7, messages: 0 });
+        connection.on_data(b"move", Kcp2KChannel::Reliable);
+        connection.on_data(b"move", Kcp2KChannel::Unreliable);
+        assert!(connection.with_user_data(|_: &u32| ()).is_none());
+        assert_eq!(connection.with_user_data(|player: &Player| (player.id, player.messages)), Some((7, 2)));
+
+        assert!(connection.take_user_data().is_some());
+        assert!(connection.with_user_data(|_: &Player| ()).is_none());
+    }
+
+    #[test]
+    fn user_data_access_inside_the_closure_never_aliases() {
+        let socket = Arc::new(bind_socket());
+        let peer = bind_socket();
+        let connection = server_connection(&socket, &peer);
+        connection.set_user_data(1u32);
+
+        // 嵌套访问得不到第二个可变引用，闭包结束后数据被放回
+        let nested = connection.with_user_data_mut(|value: &mut u32| {
+            *value += 1;
+            connection.with_user_data_mut(|_: &mut u32| ()).is_none()
+        });
+        assert_eq!(nested, Some(true));
+        assert_eq!(connection.with_user_data(|value: &u32| *value), Some(2));
+
+        // 闭包中替换的数据优先于被取出的旧数据
+        connection.with_user_data_mut(|_: &mut u32| connection.set_user_data("replaced"));
+        assert_eq!(connection.with_user_data(|value: &&str| *value), Some("replaced"));
+    }
+
+    #[test]
+    fn callback_carries_remote_address() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let socket = Arc::new(bind_socket());
+        let peer = bind_socket();
+        let callback: CallbackFuncType = Box::new(move |_, callback| {
+            tx.send(callback.remote_addr).unwrap();
+        });
+        let connection = Kcp2kConnection::new(ConnId(1), Arc::new(Kcp2KConfig::default()), Arc::new(Kcp2KMode::Server), socket.clone(), Arc::new(peer.local_addr().unwrap()), Arc::new(callback), Default::default());
+
+        connection.on_data(b"payload", Kcp2KChannel::Reliable);
+        assert_eq!(rx.try_recv().unwrap(), peer.local_addr().unwrap().as_socket());
+    }
+
+    #[test]
+    fn data_callback_receives_borrowed_payload() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        static BORROWED: AtomicUsize = AtomicUsize::new(0);
+        static OWNED: AtomicUsize = AtomicUsize::new(0);
+        fn owned_callback(_: &Kcp2kConnection, callback: Callback) {
+            if matches!(callback.r#type, CallbackType::OnData) {
+                OWNED.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+        fn data_callback(_: &Kcp2kConnection, conn_id: ConnId, data: &[u8], channel: Kcp2KChannel, tag: u8) {
+            assert_eq!(tag, 0);
+            assert_eq!(conn_id, ConnId(1));
+            assert_eq!(data, b"payload");
+            assert_eq!(channel, Kcp2KChannel::Reliable);
+            BORROWED.fetch_add(1, Ordering::SeqCst);
+        }
+
+        let socket = Arc::new(bind_socket());
+        let peer = bind_socket();
+        let data_callback_func: Arc<Option<SharedDataCallbackType>> = Default::default();
+        let connection = Kcp2kConnection::new(ConnId(1), Arc::new(Kcp2KConfig::default()), Arc::new(Kcp2KMode::Server), socket.clone(), Arc::new(peer.local_addr().unwrap()), shared_callback(owned_callback), data_callback_func.clone());
+
+        // 未设置借用回调时使用拥有所有权的 OnData
+        connection.on_data(b"payload", Kcp2KChannel::Reliable);
+        assert_eq!(OWNED.load(Ordering::SeqCst), 1);
+
+        // 设置后对现有连接立即生效，不再触发 OnData
+        data_callback_func.set_value(Some(std::sync::Arc::new(data_callback)));
+        connection.on_data(b"payload", Kcp2KChannel::Reliable);
+        assert_eq!(BORROWED.load(Ordering::SeqCst), 1);
+        assert_eq!(OWNED.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn unknown_unreliable_header_does_not_disconnect() {
+        let socket = Arc::new(bind_socket());
+        let peer = bind_socket();
+        let mut connection = server_connection(&socket, &peer);
+        connection.on_authenticated();
+
+        // 未定义的 header 以前会被当作 Disconnect 处理
+        let mut datagram: Vec<u8> = vec![Kcp2KChannel::Unreliable.into()];
+        datagram.extend_from_slice(&connection.cookie.to_le_bytes());
+        datagram.extend_from_slice(&[99, 1, 2, 3]);
+        assert!(matches!(connection.raw_input(&datagram), Err(Kcp2KError::InvalidReceive(_))));
+        assert_eq!(*connection.state, Kcp2KConnectionStates::Authenticated);
+    }
+
+    #[test]
+    fn undefined_unreliable_header_bytes_never_disconnect() {
+        let socket = Arc::new(bind_socket());
+        let peer = bind_socket();
+        let mut connection = server_connection(&socket, &peer);
+        connection.on_authenticated();
+
+        // 逐个尝试所有未定义的 header 值，伪造的数据报都只被丢弃
+        for header in (0..=u8::MAX).filter(|value| Kcp2KUnreliableHeader::try_from(*value).is_err()) {
+            let mut datagram: Vec<u8> = vec![Kcp2KChannel::Unreliable.into()];
+            datagram.extend_from_slice(&connection.cookie.to_le_bytes());
+            datagram.extend_from_slice(&[header, 1, 2, 3]);
+            assert!(matches!(connection.raw_input(&datagram), Err(Kcp2KError::InvalidReceive(_))));
+            assert_eq!(*connection.state, Kcp2KConnectionStates::Authenticated);
+        }
+        let datagrams = receive_all(&peer);
+        assert!(!datagrams.iter().any(|d| d[0] == Kcp2KChannel::Unreliable as u8 && d[5] == Kcp2KUnreliableHeader::Disconnect as u8));
+    }
+
+    #[test]
+    fn on_error_reports_the_channel_it_came_from() {
+        use std::sync::atomic::{AtomicU8, Ordering};
+        static ERROR_CHANNEL: AtomicU8 = AtomicU8::new(u8::MAX);
+        fn record_error(_: &Kcp2kConnection, callback: Callback) {
+            if matches!(callback.r#type, CallbackType::OnError) {
+                ERROR_CHANNEL.store(callback.channel.into(), Ordering::SeqCst);
+            }
+        }
+
+        let socket = Arc::new(bind_socket());
+        let peer = bind_socket();
+        let mut connection = Kcp2kConnection::new(ConnId(1), Arc::new(Kcp2KConfig::default()), Arc::new(Kcp2KMode::Server), socket.clone(), Arc::new(peer.local_addr().unwrap()), shared_callback(record_error), Default::default());
+        connection.on_authenticated();
+
+        let mut datagram: Vec<u8> = vec![Kcp2KChannel::Unreliable.into()];
+        datagram.extend_from_slice(&connection.cookie.to_le_bytes());
+        datagram.extend_from_slice(&[99, 0]);
+        assert!(connection.raw_input(&datagram).is_err());
+        assert_eq!(ERROR_CHANNEL.load(Ordering::SeqCst), Kcp2KChannel::Unreliable as u8);
+
+        assert!(connection.send_data(&[], Kcp2KChannel::Reliable).is_err());
+        assert_eq!(ERROR_CHANNEL.load(Ordering::SeqCst), Kcp2KChannel::Reliable as u8);
+    }
+
+    #[test]
+    fn too_many_segments_before_authentication_disconnects() {
+        let config = Kcp2KConfig { max_preauth_segments: 4, ..Default::default() };
+        let (_, socket_b, conn_a, mut conn_b) = connected_pair_with(config, noop_callback);
+
+        for _ in 0..8 {
+            assert!(conn_a.send_reliable(Kcp2KReliableHeader::Ping, &[]).is_ok());
+        }
+        conn_a.tick_outgoing();
+
+        let results: Vec<bool> = receive_all(&socket_b).iter().map(|datagram| conn_b.raw_input(datagram).is_ok()).collect();
+        assert!(results.contains(&false));
+        assert_eq!(*conn_b.state, Kcp2KConnectionStates::Disconnected);
+    }
+
+    #[test]
+    fn kcp_buffers_at_most_max_preauth_segments_before_authentication() {
+        let config = Kcp2KConfig { max_preauth_segments: 4, ..Default::default() };
+        let (_, socket_b, conn_a, mut conn_b) = connected_pair_with(config, noop_callback);
+
+        // 每条消息单独成包，超过上限后到达的数据包都不应再进入 KCP
+        for _ in 0..8 {
+            assert!(conn_a.send_reliable(Kcp2KReliableHeader::Ping, &[]).is_ok());
+            conn_a.flush_now();
+        }
+        let datagrams = receive_all(&socket_b);
+        assert_eq!(datagrams.len(), 8);
+        // 对端随后重传了超过上限的分段
+        for datagram in datagrams.iter().chain(&datagrams[4..]) {
+            let _ = conn_b.raw_input(datagram);
+        }
+        assert_eq!(*conn_b.state, Kcp2KConnectionStates::Disconnected);
+
+        let mut buffer = vec![0u8; 64];
+        let mut buffered = 0;
+        while conn_b.kcp.value_mut().recv(&mut buffer).is_ok() {
+            buffered += 1;
+        }
+        assert_eq!(buffered, 4);
+    }
+
+    #[test]
+    fn coalesced_unreliable_messages_share_one_datagram() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        static RECEIVED: AtomicUsize = AtomicUsize::new(0);
+        fn count_data(_: &Kcp2kConnection, callback: Callback) {
+            if matches!(callback.r#type, CallbackType::OnData) {
+                assert_eq!(callback.channel, Kcp2KChannel::Unreliable);
+                assert_eq!(callback.data.len(), 3);
+                RECEIVED.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        let config = Kcp2KConfig { coalesce_unreliable: true, unreliable_dedup: true, ..Default::default() };
+        let (_, socket_b, sender, mut receiver) = connected_pair_with(config, count_data);
+        sender.set_cookie(0x1234_5678);
+        receiver.set_cookie(0x1234_5678);
+        sender.state.set_value(Kcp2KConnectionStates::Authenticated);
+        sender.peer_confirmed.set_value(true);
+        receiver.state.set_value(Kcp2KConnectionStates::Authenticated);
+
+        for i in 0..3u8 {
+            assert!(sender.send_data(&[i; 3], Kcp2KChannel::Unreliable).is_ok());
+        }
+        // 合并的消息在 tick_outgoing 之前不会发送
+        assert!(receive_all(&socket_b).is_empty());
+        sender.tick_outgoing();
+
+        let datagrams: Vec<Vec<u8>> = receive_all(&socket_b).into_iter().filter(|d| d[0] == Kcp2KChannel::Unreliable as u8).collect();
+        assert_eq!(datagrams.len(), 1);
+        assert!(receiver.raw_input(&datagrams[0]).is_ok());
+        assert_eq!(RECEIVED.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn oversized_reliable_message_reports_fragment_limit() {
+        let socket = Arc::new(bind_socket());
+        let peer = bind_socket();
+        let connection = server_connection(&socket, &peer);
+        connection.on_authenticated();
+
+        let too_large = vec![1; connection.reliable_max_size() * 3];
+        match connection.send_data(&too_large, Kcp2KChannel::Reliable) {
+            Err(Kcp2KError::InvalidSend(message)) => assert!(message.contains("exceeds the max fragment count")),
+            _ => panic!("expected InvalidSend"),
+        }
+    }
+
+    #[test]
+    fn challenge_is_delivered_before_authentication() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        static CHALLENGES: AtomicUsize = AtomicUsize::new(0);
+        fn record_challenge(conn: &Kcp2kConnection, callback: Callback) {
+            if matches!(callback.r#type, CallbackType::OnChallenge) {
+                assert_eq!(callback.data, b"nonce");
+                assert_eq!(*conn.state, Kcp2KConnectionStates::Connected);
+                CHALLENGES.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        let server_socket = Arc::new(bind_socket());
+        let client_socket = Arc::new(bind_socket());
+        client_socket.connect(&server_socket.local_addr().unwrap()).unwrap();
+        let server = server_connection(&server_socket, &client_socket);
+        let mut client = Kcp2kConnection::new(ConnId(2), Arc::new(Kcp2KConfig::default()), Arc::new(Kcp2KMode::Client), client_socket.clone(), Arc::new(client_socket.local_addr().unwrap()), shared_callback(record_challenge), Default::default());
+
+        // 服务器在认证之前发送质询
+        assert!(server.send_challenge(b"nonce").is_ok());
+        server.tick_outgoing();
+        deliver(&client_socket, &mut client);
+        client.tick_incoming();
+        assert_eq!(CHALLENGES.load(Ordering::SeqCst), 1);
+        assert_eq!(*client.state, Kcp2KConnectionStates::Connected);
+    }
+
+    #[test]
+    fn flush_and_report_returns_bytes_written() {
+        let socket = Arc::new(bind_socket());
+        let peer = bind_socket();
+        let connection = server_connection(&socket, &peer);
+        connection.on_authenticated();
+
+        // on_authenticated 中发送的 Hello
+        let hello_bytes = connection.flush_and_report();
+        assert!(hello_bytes > 0);
+        assert!(connection.send_data(b"hello", Kcp2KChannel::Reliable).is_ok());
+        let data_bytes = connection.flush_and_report();
+        assert_eq!(data_bytes, Kcp2KConfig::METADATA_SIZE_RELIABLE + kcp::KCP_OVERHEAD + 1 + 5);
+        assert_eq!(connection.flush_and_report(), 0);
+
+        let received: usize = receive_all(&peer).iter().map(|datagram| datagram.len()).sum();
+        assert_eq!(received, hello_bytes + data_bytes);
+    }
+
+    #[test]
+    fn unreliable_loss_rate_counts_gaps_in_message_ids() {
+        let socket = Arc::new(bind_socket());
+        let peer = bind_socket();
+        let config = Kcp2KConfig { unreliable_dedup: true, ..Default::default() };
+        let connection = Kcp2kConnection::new(ConnId(1), Arc::new(config), Arc::new(Kcp2KMode::Server), socket.clone(), Arc::new(peer.local_addr().unwrap()), shared_callback(noop_callback), Default::default());
+        assert_eq!(connection.unreliable_loss_rate(), None);
+
+        // 收到 ID 10~19 中的 8 条，其中一条乱序、一条重复
+        for message_id in [10u32, 11, 13, 12, 15, 16, 16, 18, 19] {
+            let mut data = message_id.to_le_bytes().to_vec();
+            data.push(1);
+            connection.dedup_unreliable(&data);
+        }
+        assert_eq!(connection.unreliable_loss_rate(), Some(0.2));
+    }
+
+    #[test]
+    fn client_surfaces_rejection_as_disconnect() {
+        use std::sync::atomic::{AtomicU8, Ordering};
+        static REJECTED: AtomicU8 = AtomicU8::new(0);
+        fn record_rejection(_: &Kcp2kConnection, callback: Callback) {
+            if let CallbackType::OnDisconnected = callback.r#type
+                && let DisconnectReason::Rejected(reason_code) = callback.disconnect_reason
+            {
+                assert_eq!(callback.data, b"you are banned");
+                REJECTED.store(reason_code, Ordering::SeqCst);
+            }
+        }
+
+        let socket = Arc::new(bind_socket());
+        let peer = bind_socket();
+        let mut client = Kcp2kConnection::new(ConnId(1), Arc::new(Kcp2KConfig::default()), Arc::new(Kcp2KMode::Client), socket.clone(), Arc::new(peer.local_addr().unwrap()), shared_callback(record_rejection), Default::default());
+
+        let mut payload = vec![7];
+        payload.extend_from_slice(b"you are banned");
+        let datagram = crate::kcp2k_common::frame_unreliable(0, Kcp2KUnreliableHeader::Reject, &payload);
+        assert!(client.raw_input(&datagram).is_ok());
+        assert_eq!(REJECTED.load(Ordering::SeqCst), 7);
+        assert_eq!(*client.state, Kcp2KConnectionStates::Disconnected);
+    }
+
+    #[test]
+    fn take_stats_resets_rates_but_not_lifetime_totals() {
+        let socket = Arc::new(bind_socket());
+        let peer = bind_socket();
+        let connection = server_connection(&socket, &peer);
+        connection.state.set_value(Kcp2KConnectionStates::Authenticated);
+        connection.peer_confirmed.set_value(true);
+
+        assert!(connection.send_data(b"hello", Kcp2KChannel::Unreliable).is_ok());
+        let sent = connection.stats().bytes_sent;
+        assert!(sent > 0);
+
+        let taken = connection.take_stats();
+        assert_eq!(taken.bytes_sent, sent);
+        assert_eq!(connection.stats().bytes_sent, 0);
+        assert_eq!(connection.stats().packets_sent, 0);
+        assert_eq!(connection.lifetime_bytes_sent(), sent);
+
+        assert!(connection.send_data(b"hello", Kcp2KChannel::Unreliable).is_ok());
+        assert_eq!(connection.stats().bytes_sent, sent);
+        assert_eq!(connection.lifetime_bytes_sent(), sent * 2);
+    }
+
+    #[test]
+    fn strict_cookie_rejects_reliable_message_with_wrong_inner_cookie() {
+        let config = Kcp2KConfig { strict_cookie_on_reliable: true, ..Default::default() };
+        let (_, socket_b, conn_a, mut conn_b) = connected_pair_with(config, noop_callback);
+        conn_b.set_cookie(0x1234_5678);
+        conn_b.state.set_value(Kcp2KConnectionStates::Authenticated);
+
+        // 正确的 cookie 正常交付
+        conn_a.set_cookie(0x1234_5678);
+        assert!(conn_a.send_reliable(Kcp2KReliableHeader::Data, b"ok").is_ok());
+        conn_a.tick_outgoing();
+        deliver(&socket_b, &mut conn_b);
+        assert!(matches!(conn_b.receive_next_reliable(), Some((Kcp2KReliableHeader::Data, data)) if data == b"ok"));
+
+        // 外层 cookie 正确但消息内的 cookie 错误
+        conn_a.set_cookie(0x0BAD_C0DE);
+        assert!(conn_a.send_reliable(Kcp2KReliableHeader::Data, b"spoofed").is_ok());
+        conn_a.set_cookie(0x1234_5678);
+        conn_a.flush_now();
+        deliver(&socket_b, &mut conn_b);
+        assert!(conn_b.receive_next_reliable().is_none());
+        assert_eq!(*conn_b.state, Kcp2KConnectionStates::Disconnected);
+    }
+
+    #[test]
+    fn retransmission_enters_recovery_until_acknowledged() {
+        let (socket_a, socket_b, mut conn_a, mut conn_b) = connected_pair();
+
+        assert!(conn_a.send_reliable(Kcp2KReliableHeader::Data, b"lost").is_ok());
+        conn_a.tick_outgoing();
+        // 丢弃首次发送的分段
+        assert!(!receive_all(&socket_b).is_empty());
+        assert!(!conn_a.in_recovery());
+
+        // 超过重传超时后 KCP 重传该分段
+        conn_a.tick_outgoing_at(Instant::now() + Duration::from_secs(2));
+        assert!(conn_a.in_recovery());
+
+        deliver(&socket_b, &mut conn_b);
+        conn_b.flush_now();
+        deliver(&socket_a, &mut conn_a);
+        assert!(!conn_a.in_recovery());
+    }
+
+    #[test]
+    fn rtt_is_available_after_first_acknowledgement() {
+        let (socket_a, socket_b, mut conn_a, mut conn_b) = connected_pair();
+        assert!(conn_a.rtt().is_none());
+        assert!(conn_a.rtt_variance().is_none());
+
+        let now = Instant::now();
+        assert!(conn_a.send_reliable(Kcp2KReliableHeader::Data, b"hello").is_ok());
+        conn_a.tick_outgoing_at(now);
+        deliver(&socket_b, &mut conn_b);
+        conn_b.flush_now();
+        // 确认在 100 毫秒之后到达，ACK 回显的是发送时的时间戳
+        for datagram in receive_all(&socket_a) {
+            assert!(conn_a.raw_input_at(&datagram, now + Duration::from_millis(100)).is_ok());
+        }
+        assert_eq!(conn_a.rtt(), Some(Duration::from_millis(100)));
+        // 第一个样本的平均偏差为 RTT 的一半
+        assert_eq!(conn_a.rtt_variance(), Some(Duration::from_millis(50)));
+    }
+
+    #[test]
+    fn wait_snd_counts_segments_until_acknowledged() {
+        let (socket_a, socket_b, mut conn_a, mut conn_b) = connected_pair();
+
+        for _ in 0..3 {
+            assert!(conn_a.send_reliable(Kcp2KReliableHeader::Data, b"queued").is_ok());
+        }
+        assert_eq!(conn_a.wait_snd(), 3);
+        // 发出后在对端确认之前仍然计入
+        conn_a.flush_now();
+        assert_eq!(conn_a.wait_snd(), 3);
+        deliver(&socket_b, &mut conn_b);
+        conn_b.flush_now();
+        deliver(&socket_a, &mut conn_a);
+        assert_eq!(conn_a.wait_snd(), 0);
+    }
+
+    #[test]
+    fn tagged_data_is_delivered_with_its_tag() {
+        use std::sync::atomic::{AtomicU8, Ordering};
+        static LAST_TAG: AtomicU8 = AtomicU8::new(u8::MAX);
+        fn record_tag(_: &Kcp2kConnection, callback: Callback) {
+            if matches!(callback.r#type, CallbackType::OnData) {
+                assert_eq!(callback.data, b"chat");
+                LAST_TAG.store(callback.tag, Ordering::SeqCst);
+            }
+        }
+
+        let (_, socket_b, conn_a, mut conn_b) = connected_pair_with(Kcp2KConfig::default(), record_tag);
+        conn_b.set_cookie(conn_a.cookie());
+        conn_a.state.set_value(Kcp2KConnectionStates::Authenticated);
+        conn_b.state.set_value(Kcp2KConnectionStates::Authenticated);
+
+        assert!(conn_a.send_tagged(3, b"chat").is_ok());
+        conn_a.flush_now();
+        deliver(&socket_b, &mut conn_b);
+        conn_b.tick_incoming();
+        assert_eq!(LAST_TAG.load(Ordering::SeqCst), 3);
+
+        // 标签 0 即普通的可靠消息
+        assert!(conn_a.send_tagged(0, b"chat").is_ok());
+        conn_a.flush_now();
+        deliver(&socket_b, &mut conn_b);
+        conn_b.tick_incoming();
+        assert_eq!(LAST_TAG.load(Ordering::SeqCst), 0);
+        assert!(conn_a.send_tagged(1, &[]).is_err());
+    }
+
+    #[test]
+    fn data_callback_receives_tagged_data() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let (_, socket_b, conn_a, mut conn_b) = connected_pair();
+        conn_b.data_callback_func.set_value(Some(std::sync::Arc::new(move |_: &Kcp2kConnection, _: ConnId, data: &[u8], _: Kcp2KChannel, tag: u8| {
+            tx.send((tag, data.to_vec())).unwrap();
+        })));
+        conn_b.set_cookie(conn_a.cookie());
+        conn_a.state.set_value(Kcp2KConnectionStates::Authenticated);
+        conn_b.state.set_value(Kcp2KConnectionStates::Authenticated);
+
+        assert!(conn_a.send_tagged(5, b"inventory").is_ok());
+        conn_a.flush_now();
+        deliver(&socket_b, &mut conn_b);
+        conn_b.tick_incoming();
+        assert_eq!(rx.try_recv().unwrap(), (5, b"inventory".to_vec()));
+    }
+
+    #[test]
+    fn muted_connection_drops_data_but_answers_ping() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        static DATA: AtomicUsize = AtomicUsize::new(0);
+        fn count_data(_: &Kcp2kConnection, callback: Callback) {
+            if matches!(callback.r#type, CallbackType::OnData) {
+                DATA.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        let socket = Arc::new(bind_socket());
+        let peer = bind_socket();
+        let connection = Kcp2kConnection::new(ConnId(1), Arc::new(Kcp2KConfig::default()), Arc::new(Kcp2KMode::Server), socket.clone(), Arc::new(peer.local_addr().unwrap()), shared_callback(count_data), Default::default());
+        connection.state.set_value(Kcp2KConnectionStates::Authenticated);
+
+        connection.set_muted(true);
+        assert!(connection.is_muted());
+        connection.on_data(b"spam", Kcp2KChannel::Reliable);
+        assert_eq!(DATA.load(Ordering::SeqCst), 0);
+        connection.on_ping(&[0u8; 8], Kcp2KChannel::Unreliable);
+        assert!(!receive_all(&peer).is_empty());
+        assert_eq!(*connection.state, Kcp2KConnectionStates::Authenticated);
+
+        connection.set_muted(false);
+        connection.on_data(b"hello", Kcp2KChannel::Reliable);
+        assert_eq!(DATA.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn auto_ping_disabled_sends_no_ping() {
+        let socket = Arc::new(bind_socket());
+        let peer = bind_socket();
+        let config = Kcp2KConfig { auto_ping: false, is_reliable_ping: false, ..Default::default() };
+        let connection = Kcp2kConnection::new(ConnId(1), Arc::new(config), Arc::new(Kcp2KMode::Server), socket.clone(), Arc::new(peer.local_addr().unwrap()), shared_callback(noop_callback), Default::default());
+        connection.state.set_value(Kcp2KConnectionStates::Authenticated);
+
+        connection.tick_incoming_at(Instant::now() + Duration::from_millis(Kcp2KConfig::PING_INTERVAL + 100));
+        assert!(receive_all(&peer).is_empty());
+        assert_eq!(*connection.state, Kcp2KConnectionStates::Authenticated);
+    }
+
+    #[test]
+    fn latest_state_downgrades_to_unreliable_under_sustained_congestion() {
+        let socket = Arc::new(bind_socket());
+        let peer = bind_socket();
+        let config = Kcp2KConfig { downgrade_under_congestion: true, ..Default::default() };
+        let connection = Kcp2kConnection::new(ConnId(1), Arc::new(config.clone()), Arc::new(Kcp2KMode::Server), socket.clone(), Arc::new(peer.local_addr().unwrap()), shared_callback(noop_callback), Default::default());
+        connection.state.set_value(Kcp2KConnectionStates::Authenticated);
+        connection.peer_confirmed.set_value(true);
+
+        assert!(matches!(connection.send_latest_state(b"position"), Ok(Kcp2KChannel::Reliable)));
+
+        // 对端不确认，可靠队列积压超过阈值
+        for _ in 0..config.send_window_size as usize * Kcp2KConfig::DOWNGRADE_QUEUE_FACTOR {
+            assert!(connection.send_data(b"bulk", Kcp2KChannel::Reliable).is_ok());
+        }
+        let now = Instant::now();
+        connection.tick_outgoing_at(now);
+        assert!(!connection.is_congested());
+        connection.tick_outgoing_at(now + Duration::from_millis(Kcp2KConfig::DOWNGRADE_SUSTAIN));
+        assert!(connection.is_congested());
+        assert!(matches!(connection.send_latest_state(b"position"), Ok(Kcp2KChannel::Unreliable)));
+    }
+
+    #[test]
+    fn kcp_clock_restarts_once_in_flight_segments_are_acknowledged() {
+        let (socket_a, socket_b, mut conn_a, mut conn_b) = connected_pair();
+        let interval = Duration::from_millis(conn_a.config.interval as u64);
+
+        // 模拟连接已经存活了约 12.4 天，KCP 时钟即将到达重新计时的阈值
+        let mut now = conn_a.watch + Duration::from_millis(Kcp2KConfig::KCP_CLOCK_REBASE as u64) - interval * 10;
+        conn_a.tick_outgoing_at(now);
+        assert!(conn_a.send_reliable(Kcp2KReliableHeader::Data, b"before").is_ok());
+
+        // 分段在途期间按 interval tick，越过阈值；对端还没有确认，时钟不能重新计时
+        for _ in 0..20 {
+            now += interval;
+            conn_a.tick_outgoing_at(now);
+        }
+        assert!(conn_a.kcp_clock(now - conn_a.watch) >= Kcp2KConfig::KCP_CLOCK_REBASE);
+
+        // 对端确认后，下一次 tick 从 0 重新计时
+        deliver(&socket_b, &mut conn_b);
+        assert!(matches!(conn_b.receive_next_reliable(), Some((Kcp2KReliableHeader::Data, data)) if data == b"before"));
+        conn_b.flush_now();
+        deliver(&socket_a, &mut conn_a);
+        now += interval;
+        conn_a.tick_outgoing_at(now);
+        assert_eq!(conn_a.kcp_clock(now - conn_a.watch), 0);
+
+        // 重新计时后可靠消息照常发送与确认
+        assert!(conn_a.send_reliable(Kcp2KReliableHeader::Data, b"after").is_ok());
+        now += interval;
+        conn_a.tick_outgoing_at(now);
+        deliver(&socket_b, &mut conn_b);
+        assert!(matches!(conn_b.receive_next_reliable(), Some((Kcp2KReliableHeader::Data, data)) if data == b"after"));
+        conn_b.flush_now();
+        deliver(&socket_a, &mut conn_a);
+        now += interval;
+        conn_a.tick_outgoing_at(now);
+        assert_eq!(conn_a.kcp.wait_snd(), 0);
+        assert!(!conn_a.kcp.is_dead_link());
+    }
+
+    #[test]
+    fn kcp_clock_limit_disconnects_a_link_that_never_goes_idle() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        static DEAD_LINK: AtomicUsize = AtomicUsize::new(0);
+        fn record_dead_link(_: &Kcp2kConnection, callback: Callback) {
+            if matches!(callback.r#type, CallbackType::OnDisconnected) && callback.disconnect_reason == DisconnectReason::DeadLink {
+                DEAD_LINK.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        let socket = Arc::new(bind_socket());
+        let peer = bind_socket();
+        let connection = Kcp2kConnection::new(ConnId(1), Arc::new(Kcp2KConfig::default()), Arc::new(Kcp2KMode::Server), socket.clone(), Arc::new(peer.local_addr().unwrap()), shared_callback(record_dead_link), Default::default());
+        let interval = Duration::from_millis(connection.config.interval as u64);
+
+        // 对端从不确认，分段一直在途，按 interval tick 越过时钟上限后按失效链接断开
+        assert!(connection.send_reliable(Kcp2KReliableHeader::Data, b"in flight").is_ok());
+        let mut now = connection.watch + Duration::from_millis(Kcp2KConfig::KCP_CLOCK_LIMIT as u64) - interval * 10;
+        for _ in 0..5 {
+            now += interval;
+            connection.tick_outgoing_at(now);
+        }
+        assert_eq!(*connection.state, Kcp2KConnectionStates::Connected);
+        for _ in 0..10 {
+            now += interval;
+            connection.tick_outgoing_at(now);
+        }
+        assert_eq!(*connection.state, Kcp2KConnectionStates::Disconnected);
+        assert_eq!(DEAD_LINK.load(Ordering::SeqCst), 1);
+
+        // 即使继续更新 KCP 越过 2^31 与 2^32 毫秒，传给 KCP 的时间也停在上限，计时器不会溢出
+        for wrap in [1u64 << 31, 1 << 32] {
+            now = connection.watch + Duration::from_millis(wrap) - interval * 10;
+            for _ in 0..20 {
+                now += interval;
+                let current = connection.kcp_clock(now - connection.watch);
+                assert_eq!(current, Kcp2KConfig::KCP_CLOCK_LIMIT);
+                assert!(connection.kcp.value_mut().update(current).is_ok());
+            }
+        }
+        assert!(connection.kcp.wait_snd() > 0);
+    }
+
+    #[test]
+    fn timeout_enters_suspect_state_during_grace_period() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        static SUSPECT: AtomicUsize = AtomicUsize::new(0);
+        static RESUMED: AtomicUsize = AtomicUsize::new(0);
+        static DISCONNECTED: AtomicUsize = AtomicUsize::new(0);
+        fn record_events(_: &Kcp2kConnection, callback: Callback) {
+            match callback.r#type {
+                CallbackType::OnSuspect => SUSPECT.fetch_add(1, Ordering::SeqCst),
+                CallbackType::OnResumed => RESUMED.fetch_add(1, Ordering::SeqCst),
+                CallbackType::OnDisconnected => DISCONNECTED.fetch_add(1, Ordering::SeqCst),
+                _ => 0,
+            };
+        }
+
+        let socket = Arc::new(bind_socket());
+        let peer = bind_socket();
+        let config = Kcp2KConfig { disconnect_grace_ms: 500, ..Default::default() };
+        let timeout = Duration::from_millis(config.timeout);
+        let mut connection = Kcp2kConnection::new(ConnId(1), Arc::new(config), Arc::new(Kcp2KMode::Server), socket.clone(), Arc::new(peer.local_addr().unwrap()), shared_callback(record_events), Default::default());
+        connection.state.set_value(Kcp2KConnectionStates::Authenticated);
+        let now = Instant::now();
+
+        connection.tick_incoming_at(now + timeout + Duration::from_millis(100));
+        assert_eq!(*connection.state, Kcp2KConnectionStates::Suspect);
+        assert_eq!(SUSPECT.load(Ordering::SeqCst), 1);
+
+        // 宽限期内收到数据包，连接恢复
+        let mut ping: Vec<u8> = vec![Kcp2KChannel::Unreliable.into()];
+        ping.extend_from_slice(&connection.cookie.to_le_bytes());
+        ping.push(Kcp2KUnreliableHeader::Ping.into());
+        ping.extend_from_slice(&[0u8; 8]);
+        assert!(connection.raw_input(&ping).is_ok());
+        assert_eq!(*connection.state, Kcp2KConnectionStates::Authenticated);
+        assert_eq!(RESUMED.load(Ordering::SeqCst), 1);
+
+        // 宽限期内没有收到数据包，宽限期结束后才断开
+        connection.tick_incoming_at(now + timeout + Duration::from_millis(100));
+        assert_eq!(*connection.state, Kcp2KConnectionStates::Suspect);
+        connection.tick_incoming_at(now + timeout + Duration::from_millis(300));
+        assert_eq!(DISCONNECTED.load(Ordering::SeqCst), 0);
+        connection.tick_incoming_at(now + timeout + Duration::from_millis(700));
+        assert_eq!(*connection.state, Kcp2KConnectionStates::Disconnected);
+        assert_eq!(DISCONNECTED.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn lenient_invalid_receive_keeps_the_connection() {
+        let socket = Arc::new(bind_socket());
+        let peer = bind_socket();
+        let config = Kcp2KConfig { lenient_invalid_receive: true, ..Default::default() };
+        assert!(!config.should_disconnect(&Kcp2KError::InvalidReceive(String::new())));
+        assert!(config.should_disconnect(&Kcp2KError::Timeout(String::new())));
+        assert!(!config.should_disconnect(&Kcp2KError::Congestion(String::new())));
+        assert!(Kcp2KConfig::default().should_disconnect(&Kcp2KError::InvalidReceive(String::new())));
+
+        let mut connection = Kcp2kConnection::new(ConnId(1), Arc::new(config), Arc::new(Kcp2KMode::Server), socket.clone(), Arc::new(peer.local_addr().unwrap()), shared_callback(noop_callback), Default::default());
+        connection.set_cookie(0x1234_5678);
+        connection.state.set_value(Kcp2KConnectionStates::Authenticated);
+
+        // 已认证时收到 cookie 错误的数据包，只报告错误
+        let mut segment: Vec<u8> = vec![Kcp2KChannel::Unreliable.into()];
+        segment.extend_from_slice(&0x8765_4321u32.to_le_bytes());
+        segment.push(Kcp2KUnreliableHeader::Ping.into());
+        segment.push(0);
+        assert!(matches!(connection.raw_input(&segment), Err(Kcp2KError::InvalidReceive(_))));
+        assert_eq!(*connection.state, Kcp2KConnectionStates::Authenticated);
+    }
+
+    #[test]
+    fn reliable_batch_is_all_or_nothing() {
+        let socket = Arc::new(bind_socket());
+        let peer = bind_socket();
+        let connection = server_connection(&socket, &peer);
+        connection.state.set_value(Kcp2KConnectionStates::Authenticated);
+
+        assert!(connection.send_reliable_batch(&[b"a", b"b", b"c"]).is_ok());
+        assert_eq!(connection.kcp.wait_snd(), 3);
+
+        // 超过发送窗口剩余空间时一条都不排队
+        let large = vec![0u8; connection.reliable_max_size()];
+        let batch: [&[u8]; 2] = [b"first", &large];
+        assert!(matches!(connection.send_reliable_batch(&batch), Err(Kcp2KError::Congestion(_))));
+        assert_eq!(connection.kcp.wait_snd(), 3);
+
+        // 任何一条消息不合法时同样不排队
+        assert!(matches!(connection.send_reliable_batch(&[b"ok", b""]), Err(Kcp2KError::InvalidSend(_))));
+        assert_eq!(connection.kcp.wait_snd(), 3);
+    }
+
+    #[test]
+    fn unknown_headers_are_tolerated_within_budget() {
+        let socket = Arc::new(bind_socket());
+        let peer = bind_socket();
+        let config = Kcp2KConfig { unknown_header_budget: Some(2), ..Default::default() };
+        let mut connection = Kcp2kConnection::new(ConnId(1), Arc::new(config), Arc::new(Kcp2KMode::Server), socket.clone(), Arc::new(peer.local_addr().unwrap()), shared_callback(noop_callback), Default::default());
+        connection.on_authenticated();
+
+        let mut datagram: Vec<u8> = vec![Kcp2KChannel::Unreliable.into()];
+        datagram.extend_from_slice(&connection.cookie.to_le_bytes());
+        datagram.extend_from_slice(&[99, 1, 2, 3]);
+        for _ in 0..2 {
+            assert!(connection.raw_input(&datagram).is_err());
+            assert_eq!(*connection.state, Kcp2KConnectionStates::Authenticated);
+        }
+        assert_eq!(connection.unknown_header_count(), 2);
+
+        // cookie 错误的数据报不计入预算
+        let mut spoofed = datagram.clone();
+        spoofed[1] ^= 0xFF;
+        assert!(connection.raw_input(&spoofed).is_err());
+        assert_eq!(connection.unknown_header_count(), 2);
+
+        // 超过预算后断开
+        assert!(connection.raw_input(&datagram).is_err());
+        assert_eq!(*connection.state, Kcp2KConnectionStates::Disconnected);
+    }
+
+    #[test]
+    fn incoming_messages_over_the_rate_limit_are_dropped() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        static DATA: AtomicUsize = AtomicUsize::new(0);
+        fn count_data(_: &Kcp2kConnection, callback: Callback) {
+            if matches!(callback.r#type, CallbackType::OnData) {
+                DATA.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        let socket = Arc::new(bind_socket());
+        let peer = bind_socket();
+        let config = Kcp2KConfig { max_messages_per_sec_per_conn: Some(2), disconnect_on_rate_abuse: true, ..Default::default() };
+        let mut connection = Kcp2kConnection::new(ConnId(1), Arc::new(config), Arc::new(Kcp2KMode::Server), socket.clone(), Arc::new(peer.local_addr().unwrap()), shared_callback(count_data), Default::default());
+        connection.state.set_value(Kcp2KConnectionStates::Authenticated);
+
+        for _ in 0..3 {
+            connection.on_data(b"spam", Kcp2KChannel::Unreliable);
+        }
+        assert_eq!(DATA.load(Ordering::SeqCst), 2);
+        assert_eq!(connection.rate_limited_messages(), 1);
+        assert_eq!(*connection.state, Kcp2KConnectionStates::Authenticated);
+
+        // 模拟已经持续超限 RATE_ABUSE_DURATION
+        connection.watch -= Duration::from_millis(Kcp2KConfig::RATE_ABUSE_DURATION);
+        let now = connection.watch.elapsed();
+        connection.in_tokens_time.set_value(now);
+        connection.rate_abuse.set_value(Some((Duration::ZERO, now)));
+        connection.on_data(b"spam", Kcp2KChannel::Unreliable);
+        assert_eq!(DATA.load(Ordering::SeqCst), 2);
+        assert_eq!(*connection.state, Kcp2KConnectionStates::Disconnected);
+    }
+}
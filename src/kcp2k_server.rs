@@ -1,93 +1,643 @@
 use crate::kcp2k::{Kcp2K, Kcp2KMode};
-use crate::kcp2k_common::{connection_hash, CallbackFuncType, Kcp2KChannel, Kcp2KConnectionStates, Kcp2KError};
+use crate::kcp2k_common::{frame_unreliable, parse_frame, pending_recv_bytes, resolve_socket_addrs, AcceptDecision, AcceptFilterType, CallbackFuncType, ConnId, ConnIdAllocatorType, ConnectionInfo, DataCallbackFuncType, DisconnectReason, HashConnIdAllocator, Kcp2KChannel, Kcp2KConnectionStates, Kcp2KError, Kcp2KUnreliableHeader, MetricsSinkType, PreTickOutgoingType, ServerStats, TickLatencyStats};
 use crate::kcp2k_config::Kcp2KConfig;
 use crate::kcp2k_connection::Kcp2kConnection;
-use log::{error, info};
+use log::{error, info, warn};
 use revel_cell::arc::Arc;
-use socket2::SockAddr;
-use std::collections::BTreeMap;
+use socket2::{Domain, SockAddr, Socket};
+use std::collections::{HashMap, VecDeque};
 use std::io::Error;
-use std::net::SocketAddr;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::time::{Duration, Instant};
 
 pub struct Kcp2KServer {
     kcp2k: Kcp2K,
-    connections: Arc<BTreeMap<u64, Arc<Kcp2kConnection>>>,
+    sockets: Vec<Arc<Socket>>,
+    connections: Arc<HashMap<ConnId, Arc<Kcp2kConnection>>>,
+    addr_index: Arc<HashMap<SockAddr, ConnId>>,
+    // 按 cookie 查找连接，用于客户端地址变化（例如 NAT 重新映射端口）后迁移连接
+    cookie_index: Arc<HashMap<u32, ConnId>>,
+    // 等待回复的迁移质询：新地址 -> (连接 ID, 随机数, 发送时间)
+    path_challenges: Arc<HashMap<SockAddr, (ConnId, u32, Instant)>>,
+    conn_id_allocator: Arc<ConnIdAllocatorType>,
+    metrics_sink: Arc<Option<(Duration, MetricsSinkType)>>,
+    last_metrics_time: Arc<Instant>,
+    pre_tick_outgoing: Arc<Option<PreTickOutgoingType>>,
+    tick_latencies: Arc<VecDeque<Duration>>,
+    // 最近移除的连接地址与移除时间，宽限期内来自这些地址的数据包直接丢弃
+    recently_removed: Arc<HashMap<SockAddr, Instant>>,
+    accept_filter: Arc<Option<AcceptFilterType>>,
+    // 故障注入，新连接创建时复制一份
+    #[cfg(any(test, feature = "testing"))]
+    fault: Arc<crate::kcp2k_config::FaultConfig>,
 }
 
 impl Kcp2KServer {
-    fn handle_data(&self, sock_addr: &SockAddr, data: &[u8]) {
-        // 生成连接 ID
-        let conn_id = connection_hash(sock_addr);
-        // 如果连接存在，则处理数据
-        match self.connections.get(&conn_id) {
+    fn handle_data(&self, socket: &Arc<Socket>, sock_addr: &SockAddr, data: &[u8]) {
+        // 根据地址查找连接，如果连接存在，则处理数据
+        match self.addr_index.get(sock_addr).and_then(|conn_id| self.connections.get(conn_id)) {
             None => {
-                let conn_id = connection_hash(&sock_addr);
-                let kcp_server_connection = Kcp2kConnection::new(conn_id, self.kcp2k.config.clone(), Arc::new(Kcp2KMode::Server), self.kcp2k.socket.clone(), Arc::new(sock_addr.clone()), self.kcp2k.callback_func);
+                // 刚移除的连接的在途数据包，不创建新连接
+                if self.recently_removed.contains_key(sock_addr) {
+                    return;
+                }
+                // 已认证连接的客户端换了地址，迁移连接而不是创建新连接
+                if self.migrate_connection(socket, sock_addr, data) {
+                    return;
+                }
+                // 接受过滤器拒绝的地址只回复一个拒绝数据报，不分配会话
+                if let Some(filter) = self.accept_filter.value_mut()
+                    && let AcceptDecision::Reject(reason_code, message) = filter(sock_addr)
+                {
+                    self.reject(socket, sock_addr, reason_code, &message);
+                    return;
+                }
+                // 达到连接数上限时丢弃来自新地址的数据包，防止伪造源地址耗尽内存
+                let max_connections = self.kcp2k.config.max_connections;
+                if max_connections > 0 && self.connections.len() >= max_connections {
+                    warn!(instance = self.kcp2k.config.instance_name.as_deref(); "{} Dropped packet from {:?}: server is full ({} connections)", self.kcp2k.config.log_prefix(), sock_addr.as_socket(), max_connections);
+                    return;
+                }
+                // 分配连接 ID
+                let conn_id = self.conn_id_allocator.next(sock_addr);
+                let kcp_server_connection = Kcp2kConnection::new(conn_id, self.kcp2k.config.clone(), Arc::new(Kcp2KMode::Server), socket.clone(), Arc::new(sock_addr.clone()), self.kcp2k.callback_func.clone(), self.kcp2k.data_callback_func.clone());
+                #[cfg(any(test, feature = "testing"))]
+                kcp_server_connection.set_fault_injection(*self.fault);
+                self.cookie_index.value_mut().insert(kcp_server_connection.cookie(), conn_id);
                 self.connections.value_mut().insert(conn_id, Arc::new(kcp_server_connection));
+                self.addr_index.value_mut().insert(sock_addr.clone(), conn_id);
             }
             Some(conn) => {
                 if let Err(e) = conn.value_mut().raw_input(data) {
-                    error!("Error reading from data: {}", e);
+                    error!(instance = self.kcp2k.config.instance_name.as_deref(); "{} Error reading from data: {}", self.kcp2k.config.log_prefix(), e);
                 }
             }
         }
     }
+
+    // 来自未知地址的数据包携带已认证连接的 cookie 时，可能是客户端的 NAT 重新映射了端口；这对客户端不可见，只能由服务器发现。
+    // cookie 是明文，单凭它迁移会让伪造源地址的数据包劫持连接或把回复引向别处，因此先经过接受过滤器，
+    // 再通过收到数据包的 socket 向新地址发送携带随机数的 ping，只有新地址回复了回显该随机数的 pong 才迁移。
+    // 等待回复期间来自新地址的其他数据包被丢弃，可靠消息由客户端的 KCP 在迁移后重传。返回 true 表示数据包已被处理
+    fn migrate_connection(&self, socket: &Arc<Socket>, sock_addr: &SockAddr, data: &[u8]) -> bool {
+        let Ok(frame) = parse_frame(data) else {
+            return false;
+        };
+        let Some(&conn_id) = self.cookie_index.get(&frame.cookie) else {
+            return false;
+        };
+        let Some(conn) = self.connections.get(&conn_id) else {
+            return false;
+        };
+        if !conn.is_authenticated() || conn.cookie() != frame.cookie {
+            return false;
+        }
+        if let Some(filter) = self.accept_filter.value_mut()
+            && let AcceptDecision::Reject(reason_code, message) = filter(sock_addr)
+        {
+            self.reject(socket, sock_addr, reason_code, &message);
+            return true;
+        }
+        let Some(&(challenged_id, nonce, _)) = self.path_challenges.get(sock_addr) else {
+            let nonce = conn.send_path_challenge(socket, sock_addr);
+            self.path_challenges.value_mut().insert(sock_addr.clone(), (conn_id, nonce, Instant::now()));
+            return true;
+        };
+        if challenged_id != conn_id || frame.header != Some(Kcp2KUnreliableHeader::Pong) || frame.payload != nonce.to_le_bytes() {
+            return true;
+        }
+        self.path_challenges.value_mut().remove(sock_addr);
+        let now = Instant::now();
+        self.addr_index.value_mut().retain(|old_addr, id| {
+            if *id != conn_id {
+                return true;
+            }
+            info!(instance = self.kcp2k.config.instance_name.as_deref(); "{} Connection {} migrated from {:?} to {:?}", self.kcp2k.config.log_prefix(), conn_id, old_addr.as_socket(), sock_addr.as_socket());
+            // 旧地址进入 recently_removed，宽限期内旧地址的在途数据包被丢弃，不会为它创建新连接
+            self.recently_removed.value_mut().insert(old_addr.clone(), now);
+            false
+        });
+        self.addr_index.value_mut().insert(sock_addr.clone(), conn_id);
+        // 之后的回复从收到新地址数据包的 socket 发出，bind_all 时它可能与原来的 socket 不同（例如客户端从 IPv4 换到 IPv6）
+        conn.set_remote_addr(socket.clone(), sock_addr.clone());
+        true
+    }
+
+    // 向被拒绝的客户端发送拒绝数据报，负载为 [原因代码 u8][UTF-8 说明]
+    fn reject(&self, socket: &Socket, sock_addr: &SockAddr, reason_code: u8, message: &str) {
+        let mut payload = Vec::with_capacity(1 + message.len());
+        payload.push(reason_code);
+        payload.extend_from_slice(message.as_bytes());
+        // 被拒绝的客户端没有 cookie，截断说明以保证数据报不超过 MTU
+        payload.truncate(Kcp2kConnection::unreliable_max_message_size(self.kcp2k.config.mtu as u32));
+        let datagram = frame_unreliable(0, Kcp2KUnreliableHeader::Reject, &payload);
+        if let Err(e) = socket.send_to(&datagram, sock_addr) {
+            error!(instance = self.kcp2k.config.instance_name.as_deref(); "{} Failed to send rejection to {:?}: {}", self.kcp2k.config.log_prefix(), sock_addr.as_socket(), e);
+        }
+        info!(instance = self.kcp2k.config.instance_name.as_deref(); "{} Rejected connection from {:?}: {} {}", self.kcp2k.config.log_prefix(), sock_addr.as_socket(), reason_code, message);
+        // 宽限期内忽略该地址的后续数据包，避免每个重传的握手包都触发一次拒绝
+        self.recently_removed.value_mut().insert(sock_addr.clone(), Instant::now());
+    }
+
+    // 记录一次 tick 的耗时，只保留最近的样本
+    fn record_tick_latency(&self, latency: Duration) {
+        let latencies = self.tick_latencies.value_mut();
+        if latencies.len() >= Kcp2KConfig::TICK_LATENCY_SAMPLES {
+            latencies.pop_front();
+        }
+        latencies.push_back(latency);
+    }
+
+    // 按照配置的间隔调用指标回调
+    fn tick_metrics(&self) {
+        if let Some((interval, sink)) = self.metrics_sink.value_mut()
+            && self.last_metrics_time.elapsed() >= *interval
+        {
+            self.last_metrics_time.set_value(Instant::now());
+            sink(&self.stats());
+        }
+    }
 }
 
 impl Kcp2KServer {
-    pub fn new(addr: String, config: Kcp2KConfig, callback: CallbackFuncType) -> Self {
-        let kcp2k = Kcp2K::new(config, callback);
-        let socket_addr = match addr.parse::<SocketAddr>() {
-            Ok(addr) => addr,
-            Err(e) => panic!("{}", Kcp2KError::Unexpected(e.to_string())),
+    // addr 可以是 IP 字面量或 "主机名:端口"，依次尝试绑定解析出的每个地址，使用第一个绑定成功的地址
+    pub fn new(addr: String, config: Kcp2KConfig, callback: CallbackFuncType) -> Result<Self, Kcp2KError> {
+        let candidates = resolve_socket_addrs(&addr, &config)?;
+        let kcp2k = Kcp2K::new(config, callback)?;
+        let mut result = Ok(());
+        for socket_addr in candidates {
+            result = Self::bind_socket(&kcp2k.socket, socket_addr, &kcp2k.config);
+            if result.is_ok() {
+                break;
+            }
+        }
+        result?;
+        Ok(Self::with_kcp2k(kcp2k, Vec::new()))
+    }
+
+    // 解析主机名并为每个地址绑定一个 socket，所有 socket 共享同一个连接表，连接从收到数据的 socket 回复
+    pub fn bind_all(host: &str, port: u16, config: Kcp2KConfig, callback: CallbackFuncType) -> Result<Self, Kcp2KError> {
+        config.validate()?;
+        let addrs: Vec<SocketAddr> = match (host, port).to_socket_addrs() {
+            Ok(addrs) => addrs.collect(),
+            Err(e) => return Err(Kcp2KError::DnsResolve(format!("{}: {}", host, e))),
         };
-        if let Err(e) = kcp2k.socket.bind(&socket_addr.into()) {
-            panic!("{}", Kcp2KError::Unexpected(e.to_string()))
+        let mut sockets = Vec::with_capacity(addrs.len());
+        for addr in addrs {
+            let socket = Kcp2K::create_socket_with_domain(Domain::for_address(addr), &config)?;
+            // IPv6 socket 只监听 IPv6，避免与同端口的 IPv4 socket 冲突
+            if addr.is_ipv6() {
+                socket.set_only_v6(true).map_err(|e| Kcp2KError::io("failed to set IPV6_V6ONLY", &e))?;
+            }
+            Self::bind_socket(&socket, addr, &config)?;
+            sockets.push(socket);
         }
-        if let Ok(local_addr) = kcp2k.socket.local_addr()
+        let mut sockets = sockets.into_iter();
+        let primary = match sockets.next() {
+            Some(socket) => socket,
+            None => return Err(Kcp2KError::DnsResolve(format!("{}: no addresses resolved", host))),
+        };
+        let extra_sockets = sockets.map(Arc::new).collect();
+        Ok(Self::with_kcp2k(Kcp2K::with_socket(config, primary, callback)?, extra_sockets))
+    }
+
+    // 使用调用方已经创建并绑定好的 socket，不再创建与绑定，socket 的阻塞模式与缓冲区大小由调用方设置
+    pub fn from_socket(socket: Socket, config: Kcp2KConfig, callback: CallbackFuncType) -> Result<Self, Kcp2KError> {
+        Ok(Self::with_kcp2k(Kcp2K::with_socket(config, socket, callback)?, Vec::new()))
+    }
+
+    // 绑定 socket 到指定地址
+    fn bind_socket(socket: &Socket, socket_addr: SocketAddr, config: &Kcp2KConfig) -> Result<(), Kcp2KError> {
+        if let Err(e) = socket.bind(&socket_addr.into()) {
+            error!(instance = config.instance_name.as_deref(); "{} Server failed to bind on {:?}: {}", config.log_prefix(), socket_addr, e);
+            return Err(Kcp2KError::io(&format!("failed to bind {}", socket_addr), &e));
+        }
+        if let Ok(local_addr) = socket.local_addr()
             && let Some(socket_addr) = local_addr.as_socket()
         {
-            info!("[KCP2K] Server bind on: {:?}", socket_addr);
+            info!(instance = config.instance_name.as_deref(); "{} Server bind on: {:?}", config.log_prefix(), socket_addr);
+        }
+        Ok(())
+    }
+
+    fn with_kcp2k(kcp2k: Kcp2K, extra_sockets: Vec<Arc<Socket>>) -> Self {
+        let mut sockets = vec![kcp2k.socket.clone()];
+        sockets.extend(extra_sockets);
+        Kcp2KServer {
+            sockets,
+            connections: Arc::new(HashMap::new()),
+            addr_index: Arc::new(HashMap::new()),
+            cookie_index: Arc::new(HashMap::new()),
+            path_challenges: Default::default(),
+            conn_id_allocator: Arc::new(Box::new(HashConnIdAllocator)),
+            kcp2k,
+            metrics_sink: Default::default(),
+            last_metrics_time: Arc::new(Instant::now()),
+            pre_tick_outgoing: Default::default(),
+            tick_latencies: Default::default(),
+            recently_removed: Default::default(),
+            accept_filter: Default::default(),
+            #[cfg(any(test, feature = "testing"))]
+            fault: Default::default(),
         }
-        Kcp2KServer { connections: Arc::new(BTreeMap::new()), kcp2k }
     }
 
     pub fn tick(&self) {
-        self.tick_incoming();
-        self.tick_outgoing();
+        self.tick_at(Instant::now());
+    }
+
+    // 使用调用方提供的当前时间执行 tick（例如引擎的帧时间），所有连接共享同一个时间戳
+    pub fn tick_at(&self, now: Instant) {
+        self.kcp2k.record_tick(now);
+        let start = Instant::now();
+        self.tick_incoming_at(now);
+        self.tick_outgoing_at(now);
+        self.record_tick_latency(start.elapsed());
+        self.tick_metrics();
     }
 
     pub fn tick_incoming(&self) {
+        self.tick_incoming_at(Instant::now());
+    }
+
+    pub fn tick_incoming_at(&self, now: Instant) {
         // 移除断开连接的连接
-        self.connections.value_mut().retain(|_, conn| *conn.state != Kcp2KConnectionStates::Disconnected);
+        self.connections.value_mut().retain(|_, conn| {
+            if *conn.state == Kcp2KConnectionStates::Disconnected {
+                // 移除前通知，便于释放外部资源
+                conn.on_removed();
+                return false;
+            }
+            true
+        });
+        // 清理过了宽限期的地址，再记录本次移除的连接地址
+        let grace = Duration::from_millis(Kcp2KConfig::RECENTLY_REMOVED_GRACE);
+        self.recently_removed.value_mut().retain(|_, removed_at| now.saturating_duration_since(*removed_at) < grace);
+        self.addr_index.value_mut().retain(|sock_addr, conn_id| {
+            if self.connections.contains_key(conn_id) {
+                return true;
+            }
+            self.recently_removed.value_mut().insert(sock_addr.clone(), now);
+            false
+        });
+        self.cookie_index.value_mut().retain(|_, conn_id| self.connections.contains_key(conn_id));
+        // 超时未回复的迁移质询作废，来自该地址的下一个数据包重新发送质询
+        let challenge_timeout = Duration::from_millis(Kcp2KConfig::PATH_CHALLENGE_TIMEOUT);
+        self.path_challenges.value_mut().retain(|_, (conn_id, _, sent_at)| self.connections.contains_key(conn_id) && now.saturating_duration_since(*sent_at) < challenge_timeout);
 
-        while let Some((sock_addr, data)) = self.kcp2k.raw_receive_from() {
-            self.handle_data(&sock_addr, &data);
+        for socket in self.sockets.iter() {
+            self.kcp2k.receive_with_strategy_from(socket, |sock_addr, data| self.handle_data(socket, sock_addr, data));
         }
 
         for connection in self.connections.values() {
-            connection.tick_incoming();
+            connection.tick_incoming_at(now);
         }
     }
 
     pub fn tick_outgoing(&self) {
+        self.tick_outgoing_at(Instant::now());
+    }
+
+    pub fn tick_outgoing_at(&self, now: Instant) {
         for connection in self.connections.values() {
-            connection.tick_outgoing();
+            // 在 KCP 刷新之前调用钩子，便于在同一 tick 内生成并发送数据
+            if let Some(hook) = self.pre_tick_outgoing.value_mut() {
+                hook(connection);
+            }
+            connection.tick_outgoing_at(now);
+        }
+    }
+
+    pub fn connections(&self) -> impl Iterator<Item = (&ConnId, &Arc<Kcp2kConnection>)> {
+        self.connections.iter()
+    }
+
+    // 一次遍历取得所有连接的信息快照，便于整体序列化（例如状态接口）
+    pub fn all_connection_infos(&self) -> Vec<ConnectionInfo> {
+        self.connections.values().map(|conn| conn.info()).collect()
+    }
+
+    // 预留连接表容量，在已知玩家上限时提前分配，避免大量连接同时加入时扩容造成卡顿
+    pub fn reserve_connections(&self, additional: usize) {
+        self.connections.value_mut().reserve(additional);
+        self.addr_index.value_mut().reserve(additional);
+        self.cookie_index.value_mut().reserve(additional);
+    }
+
+    // 设置连接 ID 分配器，默认根据地址哈希生成连接 ID
+    pub fn set_conn_id_allocator(&self, allocator: ConnIdAllocatorType) {
+        self.conn_id_allocator.set_value(allocator);
+    }
+
+    // 设置借用形式的数据回调，设置后 OnData 事件改为通过该回调传递，对现有连接与新连接立即生效
+    pub fn set_data_callback(&self, data_callback: DataCallbackFuncType) {
+        self.kcp2k.data_callback_func.set_value(Some(data_callback.into()));
+    }
+
+    // 设置接受过滤器，每个新地址在创建连接之前调用一次，返回 Reject 时向客户端发送拒绝原因
+    pub fn set_accept_filter(&self, filter: AcceptFilterType) {
+        self.accept_filter.set_value(Some(filter));
+    }
+
+    // 设置 tick_outgoing 的前置钩子，每个连接在 KCP 刷新之前调用一次
+    pub fn set_pre_tick_outgoing(&self, hook: PreTickOutgoingType) {
+        self.pre_tick_outgoing.set_value(Some(hook));
+    }
+
+    // 设置故障注入，对现有连接与新连接立即生效，仅用于测试客户端的错误处理
+    #[cfg(any(test, feature = "testing"))]
+    pub fn set_fault_injection(&self, fault: crate::kcp2k_config::FaultConfig) {
+        self.fault.set_value(fault);
+        for connection in self.connections.values() {
+            connection.set_fault_injection(fault);
+        }
+    }
+
+    // 获取服务器统计信息快照
+    pub fn stats(&self) -> ServerStats {
+        let mut stats = ServerStats {
+            connection_count: self.connections.len(),
+            oversized_datagrams: *self.kcp2k.oversized_datagrams,
+            slow_ticks: *self.kcp2k.slow_ticks,
+            ..Default::default()
+        };
+        for (conn_id, connection) in self.connections.iter() {
+            let connection_stats = connection.stats();
+            stats.bytes_sent += connection_stats.bytes_sent;
+            stats.bytes_received += connection_stats.bytes_received;
+            stats.packets_sent += connection_stats.packets_sent;
+            stats.packets_received += connection_stats.packets_received;
+            stats.send_would_block += connection_stats.send_would_block;
+            stats.connections.push((*conn_id, connection_stats));
         }
+        stats
     }
 
-    pub fn connections(&self) -> &Arc<BTreeMap<u64, Arc<Kcp2kConnection>>> {
-        &self.connections
+    // 获取最近若干次 tick（接收与发送处理）的耗时分布
+    pub fn tick_latency_stats(&self) -> TickLatencyStats {
+        TickLatencyStats::from_samples(self.tick_latencies.iter().copied())
     }
 
-    pub fn send(&self, conn_id: u64, data: &[u8], channel: Kcp2KChannel) -> Result<(), Kcp2KError> {
+    // 设置指标回调，在 tick 中按照 interval 间隔调用
+    pub fn set_metrics_sink(&self, interval: Duration, sink: MetricsSinkType) {
+        self.last_metrics_time.set_value(Instant::now());
+        self.metrics_sink.set_value(Some((interval, sink)));
+    }
+
+    // 所有绑定的 socket 接收缓冲区当前的占用字节数之和，平台不支持时返回 None；持续接近缓冲区大小说明 tick 读取不及时
+    pub fn pending_os_recv_bytes(&self) -> Option<usize> {
+        self.sockets.iter().map(|socket| pending_recv_bytes(socket)).sum()
+    }
+
+    // 传入调用方从 socket 读取的数据报，用于 RecvStrategy::External，回复通过主 socket 发送
+    pub fn input(&self, sock_addr: &SockAddr, data: &[u8]) {
+        self.handle_data(&self.kcp2k.socket, sock_addr, data);
+    }
+
+    pub fn send(&self, conn_id: ConnId, data: &[u8], channel: Kcp2KChannel) -> Result<(), Kcp2KError> {
         if let Some(conn) = self.connections.get(&conn_id) {
             return conn.send_data(data, channel);
         }
         Err(Kcp2KError::ConnectionNotFound("Connection not found".to_string()))
     }
 
+    // 踢出指定连接，连接会在下一次 tick_incoming 时从连接表中移除
+    pub fn disconnect(&self, conn_id: ConnId) -> Result<(), Kcp2KError> {
+        match self.connections.get(&conn_id) {
+            Some(conn) => {
+                conn.disconnect();
+                Ok(())
+            }
+            None => Err(Kcp2KError::ConnectionNotFound("Connection not found".to_string())),
+        }
+    }
+
+    // 批量发送：将每条消息发送给对应的连接，返回发送失败的连接 ID 与错误
+    pub fn send_many(&self, items: &[(ConnId, &[u8], Kcp2KChannel)]) -> Vec<(ConnId, Kcp2KError)> {
+        let mut failures = Vec::new();
+        for &(conn_id, data, channel) in items {
+            match self.connections.get(&conn_id) {
+                Some(conn) => {
+                    if let Err(e) = conn.send_data(data, channel) {
+                        failures.push((conn_id, e));
+                    }
+                }
+                None => failures.push((conn_id, Kcp2KError::ConnectionNotFound("Connection not found".to_string()))),
+            }
+        }
+        failures
+    }
+
+    // 向所有已认证的连接发送同一条消息，返回发送失败的连接 ID 与错误，单个连接失败不影响其他连接
+    pub fn broadcast(&self, data: &[u8], channel: Kcp2KChannel) -> Vec<(ConnId, Kcp2KError)> {
+        self.broadcast_where(data, channel, |_| true)
+    }
+
+    // 与 broadcast 相同，但跳过指定的连接（例如消息的发送者）
+    pub fn broadcast_except(&self, skip: ConnId, data: &[u8], channel: Kcp2KChannel) -> Vec<(ConnId, Kcp2KError)> {
+        self.broadcast_where(data, channel, |conn_id| conn_id != skip)
+    }
+
+    fn broadcast_where(&self, data: &[u8], channel: Kcp2KChannel, include: impl Fn(ConnId) -> bool) -> Vec<(ConnId, Kcp2KError)> {
+        let mut failures = Vec::new();
+        for (&conn_id, conn) in self.connections.iter() {
+            if !conn.is_authenticated() || !include(conn_id) {
+                continue;
+            }
+            if let Err(e) = conn.send_data(data, channel) {
+                failures.push((conn_id, e));
+            }
+        }
+        failures
+    }
+
     pub fn stop(&self) -> Result<(), Error> {
-        self.kcp2k.socket.shutdown(std::net::Shutdown::Both)
+        // 通知所有连接服务器即将关闭
+        for connection in self.connections.values() {
+            connection.on_disconnected(DisconnectReason::ServerShutdown);
+        }
+        for socket in self.sockets.iter() {
+            socket.shutdown(std::net::Shutdown::Both)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::UdpSocket;
+
+    // 监听回环地址任意端口、忽略所有回调的服务器
+    fn test_server(config: Kcp2KConfig) -> Kcp2KServer {
+        Kcp2KServer::new("127.0.0.1:0".to_string(), config, Box::new(|_, _| {})).unwrap()
+    }
+
+    // 回环地址上的客户端地址，只用于 input，不需要真实的 socket
+    fn client_addr(port: u16) -> SockAddr {
+        SockAddr::from(SocketAddr::from(([127, 0, 0, 1], port)))
+    }
+
+    // 模拟来自 addr 的 Hello，返回服务器为该地址创建的连接 ID，被丢弃时返回 None
+    fn send_hello(server: &Kcp2KServer, addr: &SockAddr) -> Option<ConnId> {
+        server.input(addr, &[Kcp2KChannel::Reliable as u8, 0, 0, 0, 0]);
+        server.addr_index.get(addr).copied()
+    }
+
+    // 跳过握手，直接把连接标记为已认证
+    fn authenticate(server: &Kcp2KServer, conn_id: ConnId) {
+        server.connections.get(&conn_id).unwrap().state.set_value(Kcp2KConnectionStates::Authenticated);
+    }
+
+    // 真实的客户端 socket，用于检查服务器的回复
+    fn peer_socket() -> UdpSocket {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+        socket
+    }
+
+    // 从 peer 向服务器发送一个数据报，并让服务器处理一次接收
+    fn send_from(server: &Kcp2KServer, peer: &UdpSocket, datagram: &[u8]) {
+        peer.send_to(datagram, server.kcp2k.socket.local_addr().unwrap().as_socket().unwrap()).unwrap();
+        server.tick_incoming();
+    }
+
+    #[test]
+    fn new_addresses_are_dropped_at_max_connections() {
+        let server = test_server(Kcp2KConfig { max_connections: 2, ..Default::default() });
+
+        assert!(send_hello(&server, &client_addr(40001)).is_some());
+        assert!(send_hello(&server, &client_addr(40002)).is_some());
+        assert_eq!(server.connections().count(), 2);
+
+        // 新地址被丢弃，已有连接的数据包仍然正常处理
+        assert!(send_hello(&server, &client_addr(40003)).is_none());
+        assert_eq!(server.connections().count(), 2);
+        assert!(send_hello(&server, &client_addr(40001)).is_some());
+        assert_eq!(server.connections().count(), 2);
+    }
+
+    #[test]
+    fn rejected_address_receives_reason_and_no_connection() {
+        let server = test_server(Kcp2KConfig::default());
+        server.set_accept_filter(Box::new(|_| AcceptDecision::Reject(7, "banned".to_string())));
+        let peer = peer_socket();
+
+        send_from(&server, &peer, &[Kcp2KChannel::Reliable as u8, 0, 0, 0, 0]);
+        assert_eq!(server.connections().count(), 0);
+
+        let mut buf = [0u8; 1500];
+        let (size, _) = peer.recv_from(&mut buf).unwrap();
+        assert_eq!(buf[0], Kcp2KChannel::Unreliable as u8);
+        assert_eq!(buf[5], Kcp2KUnreliableHeader::Reject as u8);
+        assert_eq!(buf[6], 7);
+        assert_eq!(&buf[7..size], b"banned");
+    }
+
+    #[test]
+    fn connection_migrates_after_the_new_address_answers_the_challenge() {
+        let mut server = test_server(Kcp2KConfig::default());
+        // 第二个监听 socket，模拟 bind_all 绑定的另一个地址
+        let extra_socket = Kcp2K::create_socket_with_domain(Domain::IPV4, &server.kcp2k.config).unwrap();
+        Kcp2KServer::bind_socket(&extra_socket, SocketAddr::from(([127, 0, 0, 1], 0)), &server.kcp2k.config).unwrap();
+        let extra_addr = extra_socket.local_addr().unwrap().as_socket().unwrap();
+        server.sockets.push(Arc::new(extra_socket));
+        let old_socket = peer_socket();
+        let new_socket = peer_socket();
+
+        send_from(&server, &old_socket, &[Kcp2KChannel::Reliable as u8, 0, 0, 0, 0]);
+        let (&conn_id, conn) = server.connections().next().unwrap();
+        authenticate(&server, conn_id);
+
+        // NAT 重新映射后，客户端的数据包从新地址到达另一个监听 socket；只凭明文 cookie 不迁移，服务器从该 socket 向新地址发送质询
+        let ping = frame_unreliable(conn.cookie(), Kcp2KUnreliableHeader::Ping, &[0u8; 8]);
+        new_socket.send_to(&ping, extra_addr).unwrap();
+        server.tick_incoming();
+        assert_eq!(conn.remote_socket_addr(), Some(old_socket.local_addr().unwrap()));
+        let mut buf = [0u8; 1500];
+        let (size, from) = new_socket.recv_from(&mut buf).unwrap();
+        assert_eq!(from, extra_addr);
+        let challenge = parse_frame(&buf[..size]).ok().unwrap();
+        assert_eq!(challenge.header, Some(Kcp2KUnreliableHeader::Ping));
+        let nonce = u32::from_le_bytes(challenge.payload[4..8].try_into().unwrap());
+
+        // 回显错误随机数的 pong 不会迁移连接
+        let wrong_pong = frame_unreliable(conn.cookie(), Kcp2KUnreliableHeader::Pong, &(nonce ^ 1).to_le_bytes());
+        new_socket.send_to(&wrong_pong, extra_addr).unwrap();
+        server.tick_incoming();
+        assert_eq!(conn.remote_socket_addr(), Some(old_socket.local_addr().unwrap()));
+
+        // 回显随机数后迁移，之后的回复从收到新地址数据包的 socket 发出
+        let pong = frame_unreliable(conn.cookie(), Kcp2KUnreliableHeader::Pong, &nonce.to_le_bytes());
+        new_socket.send_to(&pong, extra_addr).unwrap();
+        server.tick_incoming();
+        assert_eq!(server.connections().count(), 1);
+        assert_eq!(conn.remote_socket_addr(), Some(new_socket.local_addr().unwrap()));
+        assert_eq!(server.addr_index.get(&SockAddr::from(new_socket.local_addr().unwrap())), Some(&conn_id));
+        assert!(server.addr_index.get(&SockAddr::from(old_socket.local_addr().unwrap())).is_none());
+        assert!(server.send(conn_id, b"moved", Kcp2KChannel::Reliable).is_ok());
+        server.tick_outgoing();
+        let (_, from) = new_socket.recv_from(&mut buf).unwrap();
+        assert_eq!(from, extra_addr);
+        assert_eq!(buf[0], Kcp2KChannel::Reliable as u8);
+
+        // 旧地址的在途数据包不会把连接迁移回去
+        send_from(&server, &old_socket, &ping);
+        assert_eq!(conn.remote_socket_addr(), Some(new_socket.local_addr().unwrap()));
+    }
+
+    #[test]
+    fn migration_runs_the_accept_filter_on_the_new_address() {
+        let server = test_server(Kcp2KConfig::default());
+        let old_socket = peer_socket();
+        let new_socket = peer_socket();
+        let banned = SockAddr::from(new_socket.local_addr().unwrap());
+        server.set_accept_filter(Box::new(move |addr| if *addr == banned { AcceptDecision::Reject(7, "banned".to_string()) } else { AcceptDecision::Accept }));
+
+        send_from(&server, &old_socket, &[Kcp2KChannel::Reliable as u8, 0, 0, 0, 0]);
+        let (&conn_id, conn) = server.connections().next().unwrap();
+        authenticate(&server, conn_id);
+
+        // 被拒绝的新地址收到拒绝数据报，不会收到质询，连接留在原来的地址
+        let ping = frame_unreliable(conn.cookie(), Kcp2KUnreliableHeader::Ping, &[0u8; 8]);
+        send_from(&server, &new_socket, &ping);
+        let mut buf = [0u8; 1500];
+        new_socket.recv_from(&mut buf).unwrap();
+        assert_eq!(buf[5], Kcp2KUnreliableHeader::Reject as u8);
+        assert!(server.path_challenges.is_empty());
+        assert_eq!(conn.remote_socket_addr(), Some(old_socket.local_addr().unwrap()));
+    }
+
+    #[test]
+    fn broadcast_reaches_authenticated_connections_except_skipped() {
+        let server = test_server(Kcp2KConfig::default());
+        let conn_ids: Vec<ConnId> = (40001..=40004).map(|port| send_hello(&server, &client_addr(port)).unwrap()).collect();
+        // 最后一个连接尚未完成握手，不参与广播
+        for &conn_id in &conn_ids[..3] {
+            authenticate(&server, conn_id);
+        }
+
+        let wait_snd = |conn_id: &ConnId| server.connections.get(conn_id).unwrap().wait_snd();
+        let before: Vec<usize> = conn_ids.iter().map(wait_snd).collect();
+        assert!(server.broadcast(b"snapshot", Kcp2KChannel::Reliable).is_empty());
+        assert!(server.broadcast_except(conn_ids[0], b"relay", Kcp2KChannel::Reliable).is_empty());
+        let queued: Vec<usize> = conn_ids.iter().zip(before).map(|(conn_id, before)| wait_snd(conn_id) - before).collect();
+        assert_eq!(queued, vec![1, 2, 2, 0]);
+    }
+
+    #[test]
+    fn broadcast_except_unknown_id_reaches_every_authenticated_connection() {
+        let server = test_server(Kcp2KConfig::default());
+        let conn_ids: Vec<ConnId> = (40001..=40003).map(|port| send_hello(&server, &client_addr(port)).unwrap()).collect();
+        for &conn_id in &conn_ids {
+            authenticate(&server, conn_id);
+        }
+        let unknown = (0..).map(ConnId).find(|conn_id| !conn_ids.contains(conn_id)).unwrap();
+
+        let wait_snd = |conn_id: &ConnId| server.connections.get(conn_id).unwrap().wait_snd();
+        let before: Vec<usize> = conn_ids.iter().map(wait_snd).collect();
+        assert!(server.broadcast_except(unknown, b"relay", Kcp2KChannel::Reliable).is_empty());
+        let queued: Vec<usize> = conn_ids.iter().zip(before).map(|(conn_id, before)| wait_snd(conn_id) - before).collect();
+        assert_eq!(queued, vec![1, 1, 1]);
     }
 }